@@ -3,7 +3,10 @@ use {
         bucket_item::BucketItem,
         bucket_map::BucketMapError,
         bucket_stats::BucketMapStats,
-        bucket_storage::{BucketStorage, DEFAULT_CAPACITY_POW2},
+        bucket_storage::{
+            BucketStorage, BucketStorageBuilder, BucketStorageConfig, MmapAdvice,
+            DEFAULT_CAPACITY_POW2,
+        },
         index_entry::IndexEntry,
         MaxSearch, RefCount,
     },
@@ -83,14 +86,15 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
         stats: Arc<BucketMapStats>,
         count: Arc<AtomicU64>,
     ) -> Self {
-        let index = BucketStorage::new(
-            Arc::clone(&drives),
-            1,
-            std::mem::size_of::<IndexEntry>() as u64,
-            max_search,
-            Arc::clone(&stats.index),
-            count,
-        );
+        let index = BucketStorageBuilder::default()
+            .drives(Arc::clone(&drives))
+            .elem(1, std::mem::size_of::<IndexEntry>() as u64)
+            .max_search(max_search)
+            .stats(Arc::clone(&stats.index))
+            .count(count)
+            .config(Self::index_config())
+            .build()
+            .unwrap();
         stats.index.resize_grow(0, index.capacity_bytes());
 
         Self {
@@ -104,6 +108,14 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
         }
     }
 
+    /// index buckets are looked up by hash, so readahead doesn't help
+    fn index_config() -> BucketStorageConfig {
+        BucketStorageConfig {
+            mmap_advice: MmapAdvice::Random,
+            ..BucketStorageConfig::default()
+        }
+    }
+
     pub fn keys(&self) -> Vec<Pubkey> {
         let mut rv = vec![];
         for i in 0..self.index.capacity() {
@@ -169,6 +181,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
             if elem.key == *key {
                 m.stop();
 
+                index.stats.record_search_distance(i - ix);
                 index
                     .stats
                     .find_entry_mut_us
@@ -183,7 +196,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
             .fetch_add(m.as_us(), Ordering::Relaxed);
         match first_free {
             Some(ii) => Ok((None, ii)),
-            None => Err(BucketMapError::IndexNoSpace(index.capacity_pow2)),
+            None => Err(BucketMapError::IndexNoSpace(index.capacity_pow2())),
         }
     }
 
@@ -200,6 +213,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
             }
             let elem: &IndexEntry = index.get(ii);
             if elem.key == *key {
+                index.stats.record_search_distance(i - ix);
                 return Some((elem, ii));
             }
         }
@@ -214,12 +228,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
     ) -> Result<u64, BucketMapError> {
         let mut m = Measure::start("bucket_create_key");
         let ix = Self::bucket_index_ix(index, key, random);
-        for i in ix..ix + index.max_search() {
-            let ii = i % index.capacity();
-            if !index.is_free(ii) {
-                continue;
-            }
-            index.allocate(ii, is_resizing).unwrap();
+        if let Ok(ii) = index.allocate_from(ix, is_resizing) {
             let elem: &mut IndexEntry = index.get_mut(ii);
             // These fields will be overwritten after allocation by callers.
             // Since this part of the mmapped file could have previously been used by someone else, there can be garbage here.
@@ -237,7 +246,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
             .stats
             .find_entry_mut_us
             .fetch_add(m.as_us(), Ordering::Relaxed);
-        Err(BucketMapError::IndexNoSpace(index.capacity_pow2))
+        Err(BucketMapError::IndexNoSpace(index.capacity_pow2()))
     }
 
     pub fn addref(&mut self, key: &Pubkey) -> Option<RefCount> {
@@ -276,11 +285,17 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
         }
         let max_search = self.index.max_search();
         let (elem, elem_ix) = Self::find_entry_mut(&mut self.index, key, self.random)?;
+        // the search distance of a new entry, recorded once the write succeeds. Otherwise the write
+        // is retried, and finding the entry records it then.
+        let mut inserted_distance = None;
         let elem = if let Some(elem) = elem {
             elem
         } else {
             let is_resizing = false;
             self.index.allocate(elem_ix, is_resizing).unwrap();
+            let capacity = self.index.capacity();
+            let ix = Self::bucket_index_ix(&self.index, key, self.random);
+            inserted_distance = Some((elem_ix + capacity - ix) % capacity);
             // These fields will be overwritten after allocation by callers.
             // Since this part of the mmapped file could have previously been used by someone else, there can be garbage here.
             let elem_allocate: &mut IndexEntry = self.index.get_mut(elem_ix);
@@ -295,8 +310,9 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
         if best_fit_bucket == bucket_ix && elem.num_slots > 0 {
             // in place update
             let elem_loc = elem.data_loc(current_bucket);
-            let slice: &mut [T] = current_bucket.get_mut_cell_slice(elem_loc, data_len as u64);
             assert!(!current_bucket.is_free(elem_loc));
+            let current_bucket = &mut self.data[bucket_ix as usize];
+            let slice: &mut [T] = current_bucket.get_mut_cell_slice(elem_loc, data_len as u64);
             elem.num_slots = num_slots;
 
             slice.iter_mut().zip(data).for_each(|(dest, src)| {
@@ -306,7 +322,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
         } else {
             // need to move the allocation to a best fit spot
             let best_bucket = &self.data[best_fit_bucket as usize];
-            let cap_power = best_bucket.capacity_pow2;
+            let cap_power = best_bucket.capacity_pow2();
             let cap = best_bucket.capacity();
             let pos = thread_rng().gen_range(0, cap);
             // max search is increased here by a lot for this search. The idea is that we just have to find an empty bucket somewhere.
@@ -323,7 +339,7 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
                     let elem_loc = elem.data_loc(current_bucket);
                     let old_slots = elem.num_slots;
                     elem.set_storage_offset(ix);
-                    elem.set_storage_capacity_when_created_pow2(best_bucket.capacity_pow2);
+                    elem.set_storage_capacity_when_created_pow2(best_bucket.capacity_pow2());
                     elem.num_slots = num_slots;
                     if old_slots > 0 {
                         let current_bucket = &mut self.data[bucket_ix as usize];
@@ -338,6 +354,9 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
                             *dest = *src;
                         });
                     }
+                    if let Some(distance) = inserted_distance {
+                        self.index.stats.record_search_distance(distance);
+                    }
                     return Ok(());
                 }
             }
@@ -361,24 +380,25 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
     }
 
     pub fn grow_index(&self, current_capacity_pow2: u8) {
-        if self.index.capacity_pow2 == current_capacity_pow2 {
+        if self.index.capacity_pow2() == current_capacity_pow2 {
             let mut m = Measure::start("grow_index");
             //debug!("GROW_INDEX: {}", current_capacity_pow2);
-            let increment = 1;
+            let increment = self.index.next_capacity_pow2() - self.index.capacity_pow2();
             for i in increment.. {
                 //increasing the capacity by ^4 reduces the
                 //likelihood of a re-index collision of 2^(max_search)^2
                 //1 in 2^32
-                let mut index = BucketStorage::new_with_capacity(
-                    Arc::clone(&self.drives),
-                    1,
-                    std::mem::size_of::<IndexEntry>() as u64,
+                let mut index = BucketStorageBuilder::default()
+                    .drives(Arc::clone(&self.drives))
+                    .elem(1, std::mem::size_of::<IndexEntry>() as u64)
                     // *2 causes rapid growth of index buckets
-                    self.index.capacity_pow2 + i, // * 2,
-                    self.index.max_search,
-                    Arc::clone(&self.stats.index),
-                    Arc::clone(&self.index.count),
-                );
+                    .capacity_pow2(self.index.capacity_pow2() + i) // * 2,
+                    .max_search(self.index.max_search)
+                    .stats(Arc::clone(&self.stats.index))
+                    .count(Arc::clone(&self.index.count))
+                    .config(Self::index_config())
+                    .build()
+                    .unwrap();
                 let random = thread_rng().gen();
                 let mut valid = true;
                 for ix in 0..self.index.capacity() {
@@ -463,11 +483,14 @@ impl<'b, T: Clone + Copy + 'static> Bucket<T> {
     /// grow a data bucket
     /// The application of the new bucket is deferred until the next write lock.
     pub fn grow_data(&self, data_index: u64, current_capacity_pow2: u8) {
+        let data_bucket = self.data.get(data_index as usize);
+        let next_capacity_pow2 =
+            data_bucket.map_or(current_capacity_pow2 + 1, BucketStorage::next_capacity_pow2);
         let new_bucket = BucketStorage::new_resized(
-            &self.drives,
+            Some(&self.drives),
             self.index.max_search,
-            self.data.get(data_index as usize),
-            std::cmp::max(current_capacity_pow2 + 1, DEFAULT_CAPACITY_POW2),
+            data_bucket,
+            std::cmp::max(next_capacity_pow2, DEFAULT_CAPACITY_POW2),
             1 << data_index,
             Self::elem_size(),
             &self.stats.data,
@@ -0,0 +1,212 @@
+use {
+    memmap2::MmapMut,
+    rand::{thread_rng, Rng},
+    std::{
+        fs::{remove_file, OpenOptions},
+        io,
+        ops::Range,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    },
+};
+
+/// regions are handed out in multiples of this, so every region starts on a page boundary
+const REGION_ALIGN: usize = 4096;
+
+/// One file shared by many `BucketStorage`s, each mapped to its own region of the file.
+/// This keeps the number of files on disk down when there are many small buckets.
+/// The file is removed when the arena and every region allocated from it are dropped.
+pub struct BucketArena {
+    path: PathBuf,
+    mmap: MmapMut,
+    /// start of 'mmap', taken while it was still uniquely owned so regions can write through it
+    ptr: *mut u8,
+    /// unallocated byte ranges of the file, sorted by start and never adjacent
+    free: Mutex<Vec<Range<usize>>>,
+}
+
+// regions only access disjoint ranges of the mapping, the free list is behind a mutex
+unsafe impl Send for BucketArena {}
+unsafe impl Sync for BucketArena {}
+
+impl BucketArena {
+    /// create a file of at least 'len' bytes on 'drive' to allocate regions from
+    pub fn new(drive: &Path, len: usize) -> io::Result<Self> {
+        let len = round_up(len.max(1));
+        let random = thread_rng().gen_range(0, u128::MAX);
+        let path = drive.join(format!("arena.{random}"));
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let map_file = || {
+            data.set_len(len as u64)?;
+            unsafe { MmapMut::map_mut(&data) }
+        };
+        let mut mmap = match map_file() {
+            Ok(mmap) => mmap,
+            Err(err) => {
+                let _ = remove_file(&path);
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            path,
+            ptr: mmap.as_mut_ptr(),
+            mmap,
+            free: Mutex::new(vec![0..len]),
+        })
+    }
+
+    /// path of the file backing this arena
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// size of the file backing this arena
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// never true, `new` rounds the size up to at least `REGION_ALIGN` bytes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of bytes not currently allocated to a region
+    pub fn free_bytes(&self) -> usize {
+        self.free
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|range| range.len())
+            .sum()
+    }
+
+    /// allocate a zeroed region of at least 'len' bytes, or None if no free range is large enough
+    pub fn allocate(self: &Arc<Self>, len: usize) -> Option<ArenaRegion> {
+        let len = round_up(len.max(1));
+        let mut free = self.free.lock().unwrap();
+        let i = free.iter().position(|range| range.len() >= len)?;
+        let offset = free[i].start;
+        free[i].start += len;
+        if free[i].is_empty() {
+            free.remove(i);
+        }
+        Some(ArenaRegion {
+            arena: Arc::clone(self),
+            offset,
+            len,
+        })
+    }
+
+    /// return 'range' to the free list, merging it with its neighbors
+    fn release(&self, range: Range<usize>) {
+        let mut free = self.free.lock().unwrap();
+        let i = free.partition_point(|free| free.start < range.start);
+        let mut range = range;
+        if i < free.len() && free[i].start == range.end {
+            range.end = free.remove(i).end;
+        }
+        if i > 0 && free[i - 1].end == range.start {
+            free[i - 1].end = range.end;
+        } else {
+            free.insert(i, range);
+        }
+    }
+}
+
+impl Drop for BucketArena {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+fn round_up(len: usize) -> usize {
+    (len + REGION_ALIGN - 1) / REGION_ALIGN * REGION_ALIGN
+}
+
+/// A region of a `BucketArena`, at 'offset' bytes from the start of its file.
+/// The region is zeroed and returned to the arena when dropped.
+pub struct ArenaRegion {
+    arena: Arc<BucketArena>,
+    offset: usize,
+    len: usize,
+}
+
+impl ArenaRegion {
+    /// offset of this region in the file backing the arena
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn arena(&self) -> &Arc<BucketArena> {
+        &self.arena
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.arena.ptr.add(self.offset), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.arena.ptr.add(self.offset), self.len) }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.arena.mmap.flush_range(self.offset, self.len)
+    }
+
+    pub fn flush_async(&self) -> io::Result<()> {
+        self.arena.mmap.flush_async_range(self.offset, self.len)
+    }
+}
+
+impl Drop for ArenaRegion {
+    fn drop(&mut self) {
+        // the next owner of this range expects it to be zeroed, like a new file
+        self.as_mut_slice().fill(0);
+        self.arena.release(self.offset..self.offset + self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tempfile::tempdir};
+
+    #[test]
+    fn test_arena_allocate_and_release() {
+        let tmpdir = tempdir().unwrap();
+        let arena = Arc::new(BucketArena::new(tmpdir.path(), 4 * REGION_ALIGN).unwrap());
+        assert_eq!(arena.len(), 4 * REGION_ALIGN);
+        let path = arena.path().to_path_buf();
+        assert!(path.exists());
+
+        let mut a = arena.allocate(1).unwrap();
+        let b = arena.allocate(REGION_ALIGN + 1).unwrap();
+        let c = arena.allocate(REGION_ALIGN).unwrap();
+        assert_eq!(
+            (a.offset(), b.offset(), c.offset()),
+            (0, REGION_ALIGN, 3 * REGION_ALIGN)
+        );
+        assert_eq!(b.as_slice().len(), 2 * REGION_ALIGN);
+        assert_eq!(arena.free_bytes(), 0);
+        assert!(arena.allocate(1).is_none());
+
+        a.as_mut_slice().fill(1);
+        drop(a);
+        drop(c);
+        assert_eq!(arena.free_bytes(), 2 * REGION_ALIGN);
+        // the free ranges are not adjacent
+        assert!(arena.allocate(2 * REGION_ALIGN).is_none());
+        drop(b);
+        // every range was merged back together
+        let all = arena.allocate(4 * REGION_ALIGN).unwrap();
+        assert!(all.as_slice().iter().all(|byte| *byte == 0));
+
+        drop(arena);
+        assert!(path.exists());
+        drop(all);
+        assert!(!path.exists());
+    }
+}
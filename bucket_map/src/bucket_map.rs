@@ -197,6 +197,30 @@ mod tests {
         assert_eq!(index.read_value(&key), Some((vec![0], 0)));
     }
 
+    #[test]
+    fn bucket_map_test_search_distance_histogram() {
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let recorded = || {
+            index
+                .stats
+                .index
+                .search_distance_histogram()
+                .iter()
+                .sum::<u64>()
+        };
+        // keys re-inserted when the index grows are not counted again
+        keys.iter().for_each(|key| index.insert(key, (&[0], 0)));
+        assert_eq!(recorded(), keys.len() as u64);
+        keys.iter()
+            .for_each(|key| assert_eq!(index.read_value(key), Some((vec![0], 0))));
+        assert_eq!(recorded(), 2 * keys.len() as u64);
+        // nor are misses
+        assert_eq!(index.read_value(&Pubkey::new_unique()), None);
+        assert_eq!(recorded(), 2 * keys.len() as u64);
+    }
+
     #[test]
     fn bucket_map_test_insert2() {
         for pass in 0..3 {
@@ -1,22 +1,144 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use {
+    crate::MaxSearch,
+    std::{
+        collections::VecDeque,
+        fmt,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{SystemTime, UNIX_EPOCH},
+    },
 };
 
+/// number of the most recent resizes kept by `BucketStats::growth_history`
+pub const GROWTH_HISTORY_LEN: usize = 8;
+
+/// one slot for every probe distance a `MaxSearch` can describe
+const SEARCH_DISTANCE_SLOTS: usize = MaxSearch::MAX as usize + 1;
+
+/// number of probes, indexed by how many cells past the hashed cell the probe walked
+#[derive(Debug)]
+pub struct SearchDistanceHistogram([AtomicU64; SEARCH_DISTANCE_SLOTS]);
+
+impl Default for SearchDistanceHistogram {
+    fn default() -> Self {
+        Self(std::array::from_fn(|_| AtomicU64::default()))
+    }
+}
+
+/// one resize, as recorded in `BucketStats::growth_history`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrowthRecord {
+    /// microseconds since the unix epoch
+    pub timestamp_us: u64,
+    pub old_capacity_pow2: u8,
+    pub new_capacity_pow2: u8,
+}
+
+/// time spent creating files on one drive, see `BucketStats::with_drives`
+#[derive(Debug, Default)]
+pub struct DriveStats {
+    /// files created on this drive, including ones since removed
+    pub files_created: AtomicU64,
+    pub new_file_us: AtomicU64,
+    pub flush_file_us: AtomicU64,
+    pub mmap_us: AtomicU64,
+}
+
+/// Counters shared by a family of bucket storages.
+/// Most are cumulative (times, numbers of operations, the search distance histogram) and are
+/// cleared by `reset`. The rest describe live state and are kept: `max_size`, `file_count`,
+/// `total_file_size`, `bytes_allocated` and `bytes_freed` (so `net_bytes_on_disk` stays right),
+/// `next_drive` and the growth history.
+/// Created with `with_drives`, the times of creating files are also broken down by drive.
 #[derive(Debug, Default)]
 pub struct BucketStats {
     pub resizes: AtomicU64,
     pub max_size: AtomicU64,
     pub resize_us: AtomicU64,
+    /// bytes of allocated cells copied by resizes
+    pub resize_bytes_copied: AtomicU64,
+    /// bytes of cells, allocated or not, examined by resizes
+    pub resize_bytes_scanned: AtomicU64,
     pub new_file_us: AtomicU64,
     pub flush_file_us: AtomicU64,
+    pub explicit_flush_us: AtomicU64,
+    /// flushes started because of `FlushPolicy::EveryNWrites`
+    pub policy_flushes: AtomicU64,
     pub mmap_us: AtomicU64,
+    /// mappings retried because they failed with ENOMEM
+    pub mmap_retries: AtomicU64,
+    /// time spent faulting in mappings with `BucketStorage::warmup`
+    pub warmup_us: AtomicU64,
+    /// mappings that could not be locked in memory for `BucketStorageConfig::mlock_on_open`
+    pub mlock_failures: AtomicU64,
     pub find_entry_mut_us: AtomicU64,
     pub file_count: AtomicU64,
     pub total_file_size: AtomicU64,
+    /// bytes of bucket files created
+    pub bytes_allocated: AtomicU64,
+    /// bytes of bucket files removed, of those counted in `bytes_allocated`.
+    /// A file reopened with `BucketStorage::open_existing` was not created by its storage, so
+    /// removing it is not counted.
+    pub bytes_freed: AtomicU64,
+    /// files created with `DriveSelection::RoundRobin`, picks the drive of the next one
+    pub next_drive: AtomicU64,
+    pub search_distance: SearchDistanceHistogram,
+    /// mutable cell accesses timed because of `BucketStorageConfig::write_sample_rate`
+    pub write_sample_count: AtomicU64,
+    /// total time of the sampled accesses, divide by `write_sample_count` for the average
+    pub write_sample_us: AtomicU64,
+    /// longest sampled access
+    pub write_sample_max_us: AtomicU64,
+    /// the last `GROWTH_HISTORY_LEN` resizes, oldest first
+    growth_history: Mutex<VecDeque<GrowthRecord>>,
+    /// indexed like the drives of the storages sharing these stats, empty unless `with_drives`
+    per_drive: Vec<DriveStats>,
 }
 
 impl BucketStats {
+    /// stats that also keep the times of creating files on each of 'num_drives' drives, so a slow
+    /// drive stands out. The storages sharing them must all use the same list of drives.
+    pub fn with_drives(num_drives: usize) -> Self {
+        Self {
+            per_drive: (0..num_drives).map(|_| DriveStats::default()).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// times of creating files on each drive, indexed like the drives.
+    /// None unless these stats were created with `with_drives`.
+    pub fn per_drive(&self) -> Option<&[DriveStats]> {
+        (!self.per_drive.is_empty()).then_some(self.per_drive.as_slice())
+    }
+
+    /// add the times of creating one file on the drive at 'drive_ix' to the totals,
+    /// and to that drive's breakdown if these stats keep one
+    pub fn record_new_file(
+        &self,
+        drive_ix: usize,
+        new_file_us: u64,
+        flush_file_us: u64,
+        mmap_us: u64,
+    ) {
+        for (counter, us) in [
+            (&self.new_file_us, new_file_us),
+            (&self.flush_file_us, flush_file_us),
+            (&self.mmap_us, mmap_us),
+        ] {
+            counter.fetch_add(us, Ordering::Relaxed);
+        }
+        if let Some(drive) = self.per_drive.get(drive_ix) {
+            drive.files_created.fetch_add(1, Ordering::Relaxed);
+            drive.new_file_us.fetch_add(new_file_us, Ordering::Relaxed);
+            drive
+                .flush_file_us
+                .fetch_add(flush_file_us, Ordering::Relaxed);
+            drive.mmap_us.fetch_add(mmap_us, Ordering::Relaxed);
+        }
+    }
+
     pub fn update_max_size(&self, size: u64) {
         self.max_size.fetch_max(size, Ordering::Relaxed);
     }
@@ -26,6 +148,166 @@ impl BucketStats {
         self.total_file_size
             .fetch_add(size_change, Ordering::Relaxed);
     }
+
+    /// remember a resize from 2^'old_capacity_pow2' to 2^'new_capacity_pow2' cells,
+    /// forgetting the oldest one if `GROWTH_HISTORY_LEN` are already kept
+    pub fn record_growth(&self, old_capacity_pow2: u8, new_capacity_pow2: u8) {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_micros() as u64)
+            .unwrap_or_default();
+        let mut history = self.growth_history.lock().unwrap();
+        if history.len() == GROWTH_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(GrowthRecord {
+            timestamp_us,
+            old_capacity_pow2,
+            new_capacity_pow2,
+        });
+    }
+
+    /// the most recent resizes, oldest first, so the lead up to a blow up can be seen after the fact
+    pub fn growth_history(&self) -> Vec<GrowthRecord> {
+        self.growth_history
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// bytes of bucket files that have been created and not yet removed
+    pub fn net_bytes_on_disk(&self) -> u64 {
+        self.bytes_allocated
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.bytes_freed.load(Ordering::Relaxed))
+    }
+
+    /// zero every cumulative counter, so the next sample covers only the time since this call.
+    /// Live state is kept, see `BucketStats`.
+    pub fn reset(&self) {
+        for counter in [
+            &self.resizes,
+            &self.resize_us,
+            &self.resize_bytes_copied,
+            &self.resize_bytes_scanned,
+            &self.new_file_us,
+            &self.flush_file_us,
+            &self.explicit_flush_us,
+            &self.policy_flushes,
+            &self.mmap_us,
+            &self.mmap_retries,
+            &self.warmup_us,
+            &self.mlock_failures,
+            &self.find_entry_mut_us,
+            &self.write_sample_count,
+            &self.write_sample_us,
+            &self.write_sample_max_us,
+        ]
+        .into_iter()
+        .chain(&self.search_distance.0)
+        .chain(self.per_drive.iter().flat_map(|drive| {
+            [
+                &drive.files_created,
+                &drive.new_file_us,
+                &drive.flush_file_us,
+                &drive.mmap_us,
+            ]
+        })) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// every counter as a (name, value) pair, named after its field, for feeding a metrics registry.
+    /// The search distance histogram is not included, see `search_distance_histogram`.
+    pub fn metrics(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("resizes", &self.resizes),
+            ("max_size", &self.max_size),
+            ("resize_us", &self.resize_us),
+            ("resize_bytes_copied", &self.resize_bytes_copied),
+            ("resize_bytes_scanned", &self.resize_bytes_scanned),
+            ("new_file_us", &self.new_file_us),
+            ("flush_file_us", &self.flush_file_us),
+            ("explicit_flush_us", &self.explicit_flush_us),
+            ("policy_flushes", &self.policy_flushes),
+            ("mmap_us", &self.mmap_us),
+            ("mmap_retries", &self.mmap_retries),
+            ("warmup_us", &self.warmup_us),
+            ("mlock_failures", &self.mlock_failures),
+            ("find_entry_mut_us", &self.find_entry_mut_us),
+            ("file_count", &self.file_count),
+            ("total_file_size", &self.total_file_size),
+            ("bytes_allocated", &self.bytes_allocated),
+            ("bytes_freed", &self.bytes_freed),
+            ("next_drive", &self.next_drive),
+            ("write_sample_count", &self.write_sample_count),
+            ("write_sample_us", &self.write_sample_us),
+            ("write_sample_max_us", &self.write_sample_max_us),
+        ]
+        .into_iter()
+        .map(|(name, counter)| (name, counter.load(Ordering::Relaxed)))
+        .collect()
+    }
+
+    /// record a successful probe that walked 'distance' cells past the hashed cell: a lookup that
+    /// found its key, or an insert into a free cell. Misses, and the re-inserts of a resize, are
+    /// not recorded.
+    pub fn record_search_distance(&self, distance: u64) {
+        let slot = (distance as usize).min(SEARCH_DISTANCE_SLOTS - 1);
+        self.search_distance.0[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of probes at each distance, from 0 up to the largest possible `MaxSearch`
+    pub fn search_distance_histogram(&self) -> Vec<u64> {
+        self.search_distance
+            .0
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// microseconds as milliseconds, for readability
+fn ms(us: &AtomicU64) -> f64 {
+    us.load(Ordering::Relaxed) as f64 / 1_000.0
+}
+
+/// One line summary of the counters, for periodic logging.
+/// Times are in milliseconds. Only non-zero search distances are listed, as 'distance:probes'.
+impl fmt::Display for BucketStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "resizes: {}, resize: {:.3}ms, max_size: {}, files: {}, total_file_size: {}, \
+             net_bytes_on_disk: {}, new_file: {:.3}ms, flush_file: {:.3}ms, explicit_flush: {:.3}ms, \
+             mmap: {:.3}ms, find_entry_mut: {:.3}ms, search_distance: [",
+            load(&self.resizes),
+            ms(&self.resize_us),
+            load(&self.max_size),
+            load(&self.file_count),
+            load(&self.total_file_size),
+            self.net_bytes_on_disk(),
+            ms(&self.new_file_us),
+            ms(&self.flush_file_us),
+            ms(&self.explicit_flush_us),
+            ms(&self.mmap_us),
+            ms(&self.find_entry_mut_us),
+        )?;
+        let histogram = self.search_distance_histogram();
+        let mut separator = "";
+        for (distance, probes) in histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, probes)| **probes > 0)
+        {
+            write!(f, "{separator}{distance}:{probes}")?;
+            separator = " ";
+        }
+        write!(f, "]")
+    }
 }
 
 #[derive(Debug, Default)]
@@ -33,3 +315,128 @@ pub struct BucketMapStats {
     pub index: Arc<BucketStats>,
     pub data: Arc<BucketStats>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_distance_histogram() {
+        let stats = BucketStats::default();
+        assert!(stats.search_distance_histogram().iter().all(|c| *c == 0));
+
+        stats.record_search_distance(0);
+        stats.record_search_distance(0);
+        stats.record_search_distance(3);
+        // distances beyond any possible max_search land in the last slot
+        stats.record_search_distance(u64::MAX);
+
+        let histogram = stats.search_distance_histogram();
+        assert_eq!(histogram.len(), MaxSearch::MAX as usize + 1);
+        assert_eq!(histogram[0], 2);
+        assert_eq!(histogram[3], 1);
+        assert_eq!(histogram[MaxSearch::MAX as usize], 1);
+        assert_eq!(histogram.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_display() {
+        let stats = BucketStats::default();
+        stats.resizes.store(3, Ordering::Relaxed);
+        stats.resize_us.store(1_500, Ordering::Relaxed);
+        stats.record_search_distance(0);
+        stats.record_search_distance(2);
+        stats.record_search_distance(2);
+        let summary = stats.to_string();
+        assert!(
+            summary.starts_with("resizes: 3, resize: 1.500ms,"),
+            "{summary}"
+        );
+        assert!(summary.ends_with("search_distance: [0:1 2:2]"), "{summary}");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let stats = BucketStats::default();
+        let names = stats
+            .metrics()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        for name in [
+            "new_file_us",
+            "flush_file_us",
+            "mmap_us",
+            "resizes",
+            "resize_us",
+            "mmap_retries",
+            "warmup_us",
+            "bytes_allocated",
+            "bytes_freed",
+        ] {
+            assert!(names.contains(&name), "{name} missing from {names:?}");
+        }
+        assert!(stats.metrics().iter().all(|(_, value)| *value == 0));
+
+        stats.resizes.fetch_add(2, Ordering::Relaxed);
+        stats.mmap_us.fetch_add(7, Ordering::Relaxed);
+        let metrics = stats.metrics();
+        let value = |name| metrics.iter().find(|(n, _)| *n == name).unwrap().1;
+        assert_eq!(value("resizes"), 2);
+        assert_eq!(value("mmap_us"), 7);
+        assert_eq!(value("new_file_us"), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let stats = BucketStats::default();
+        stats.resizes.store(3, Ordering::Relaxed);
+        stats.resize_us.store(1_500, Ordering::Relaxed);
+        stats.mmap_us.store(20, Ordering::Relaxed);
+        stats.write_sample_max_us.store(9, Ordering::Relaxed);
+        stats.record_search_distance(2);
+        stats.file_count.store(4, Ordering::Relaxed);
+        stats.bytes_allocated.store(100, Ordering::Relaxed);
+        stats.bytes_freed.store(40, Ordering::Relaxed);
+        stats.update_max_size(64);
+        stats.record_growth(5, 6);
+
+        stats.reset();
+        for name in ["resizes", "resize_us", "mmap_us", "write_sample_max_us"] {
+            let (_, value) = stats
+                .metrics()
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .unwrap();
+            assert_eq!(value, 0, "{name}");
+        }
+        assert!(stats.search_distance_histogram().iter().all(|c| *c == 0));
+        // live state is untouched
+        assert_eq!(stats.file_count.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.net_bytes_on_disk(), 60);
+        assert_eq!(stats.max_size.load(Ordering::Relaxed), 64);
+        assert_eq!(stats.growth_history().len(), 1);
+    }
+
+    #[test]
+    fn test_per_drive() {
+        assert!(BucketStats::default().per_drive().is_none());
+        let stats = BucketStats::with_drives(2);
+        assert_eq!(stats.per_drive().unwrap().len(), 2);
+        stats.record_new_file(1, 4, 5, 6);
+        // a drive the stats were not created with only counts towards the totals
+        stats.record_new_file(2, 1, 1, 1);
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let per_drive = stats.per_drive().unwrap();
+        assert_eq!(load(&per_drive[0].files_created), 0);
+        assert_eq!(load(&per_drive[0].new_file_us), 0);
+        assert_eq!(load(&per_drive[1].files_created), 1);
+        assert_eq!(load(&per_drive[1].new_file_us), 4);
+        assert_eq!(load(&per_drive[1].flush_file_us), 5);
+        assert_eq!(load(&per_drive[1].mmap_us), 6);
+        assert_eq!(load(&stats.mmap_us), 7);
+
+        stats.reset();
+        assert_eq!(load(&stats.per_drive().unwrap()[1].mmap_us), 0);
+    }
+}
@@ -1,16 +1,27 @@
 use {
-    crate::{bucket_stats::BucketStats, MaxSearch},
-    memmap2::MmapMut,
-    rand::{thread_rng, Rng},
+    crate::{
+        bucket_arena::{ArenaRegion, BucketArena},
+        bucket_stats::BucketStats,
+        MaxSearch,
+    },
+    memmap2::{Mmap, MmapMut},
+    rand::{seq::SliceRandom, thread_rng, Rng},
+    rayon::prelude::*,
     solana_measure::measure::Measure,
     std::{
-        fs::{remove_file, OpenOptions},
-        io::{Seek, SeekFrom, Write},
-        path::PathBuf,
+        collections::HashSet,
+        fs::{remove_file, File, OpenOptions},
+        io::{self, Read, Write},
+        marker::PhantomData,
+        mem::ManuallyDrop,
+        ops::{Deref, DerefMut, Range},
+        path::{Path, PathBuf},
         sync::{
             atomic::{AtomicU64, Ordering},
-            Arc,
+            Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
         },
+        thread::JoinHandle,
+        time::Duration,
     },
 };
 
@@ -33,396 +44,8018 @@ use {
 24  16,777,216
 */
 pub const DEFAULT_CAPACITY_POW2: u8 = 5;
+/// largest capacity a storage can be created or grown to
+pub const MAX_CAPACITY_POW2: u8 = 31;
+/// largest capacity chosen by `capacity_pow2_for_elements`
+pub const MAX_PRESIZED_CAPACITY_POW2: u8 = 24;
 
-#[derive(Debug, PartialEq, Eq)]
-enum IsAllocatedFlagLocation {
-    /// 'allocated' flag per entry is stored in a u64 header per entry
-    InHeader,
+/// smallest capacity_pow2 that holds 'num_elems' cells with occupancy at or below 'target_occupancy'.
+/// Clamped to `MAX_PRESIZED_CAPACITY_POW2`.
+pub fn capacity_pow2_for_elements(num_elems: u64, target_occupancy: f64) -> u8 {
+    min_capacity_pow2(num_elems, target_occupancy).min(MAX_PRESIZED_CAPACITY_POW2 as u32) as u8
 }
 
-const IS_ALLOCATED_FLAG_LOCATION: IsAllocatedFlagLocation = IsAllocatedFlagLocation::InHeader;
+/// same as `capacity_pow2_for_elements`, without the clamp
+fn min_capacity_pow2(num_elems: u64, target_occupancy: f64) -> u32 {
+    assert!(
+        target_occupancy > 0.0 && target_occupancy <= 1.0,
+        "target_occupancy must be in (0, 1]: {target_occupancy}"
+    );
+    let min_capacity = (num_elems as f64 / target_occupancy).ceil() as u64;
+    min_capacity
+        .checked_next_power_of_two()
+        .map(u64::trailing_zeros)
+        .unwrap_or(u64::BITS)
+}
 
-/// A Header UID of 0 indicates that the header is unlocked
-const UID_UNLOCKED: Uid = 0;
-/// uid in maps is 1 or 0, where 0 is empty, 1 is in-use
-const UID_LOCKED: Uid = 1;
+/// occupancy `BucketStorage::fit_pow2` sizes a storage for
+pub const FIT_LOAD_FACTOR: f64 = 0.5;
+
+/// buckets with fewer cells than this are copied on the calling thread when resized
+const PARALLEL_COPY_MIN_CELLS: u64 = 1 << 14;
+/// number of source cells each rayon task copies when resizing
+const PARALLEL_COPY_CHUNK_CELLS: u64 = 1 << 12;
+/// number of cells each rayon task scans in `par_allocated_indices`
+const PARALLEL_SCAN_CHUNK_CELLS: u64 = 1 << 12;
+/// `BucketStorage::warmup` does nothing for mappings smaller than this
+const WARMUP_MIN_BYTES: usize = 1 << 20;
+/// `BucketStorage::warmup` reads one byte this far apart, the smallest page size in common use
+const WARMUP_STRIDE: usize = 4096;
+/// number of times mapping a new file is retried when it fails with ENOMEM
+const MMAP_ENOMEM_RETRIES: u32 = 5;
+/// wait before the first retry of a mapping that failed with ENOMEM, doubled for every retry after
+const MMAP_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Where the 'allocated' flag of each cell is stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IsAllocatedFlagLocation {
+    /// 'allocated' flag per entry is stored in a u64 header per entry
+    #[default]
+    InHeader,
+    /// 'allocated' flags are stored one bit per entry, in a bitmap between the prologue and the
+    /// first cell. Cells have no header, so `allocate_with_uid` is not supported.
+    Bitmap,
+}
 
+/// bytes reserved after the header of each cell for the cell's crc32 when checksums are enabled.
 /// u64 for purposes of 8 byte alignment
-/// We only need 1 bit of this.
-type Uid = u64;
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u64>();
 
-#[repr(C)]
-struct Header {
-    lock: u64,
+/// Access pattern hint given to the kernel for a bucket's mapping, see madvise(2).
+/// Ignored on non-unix platforms.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MmapAdvice {
+    /// no hint, the kernel's default readahead is used
+    #[default]
+    Normal,
+    /// pages will be accessed in random order, so readahead is not useful.
+    /// This is the case for index buckets, which are looked up by hash.
+    Random,
+    /// pages will be accessed in sequential order
+    Sequential,
+    /// pages will be accessed soon, so read them in now
+    WillNeed,
 }
 
-impl Header {
-    /// try to lock this entry with 'uid'
-    /// return true if it could be locked
-    fn try_lock(&mut self) -> bool {
-        if self.lock == UID_UNLOCKED {
-            self.lock = UID_LOCKED;
-            true
+impl MmapAdvice {
+    /// give this advice for the pages containing 'data'
+    #[cfg(unix)]
+    fn advise(self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let advice = match self {
+            MmapAdvice::Normal => libc::MADV_NORMAL,
+            MmapAdvice::Random => libc::MADV_RANDOM,
+            MmapAdvice::Sequential => libc::MADV_SEQUENTIAL,
+            MmapAdvice::WillNeed => libc::MADV_WILLNEED,
+        };
+        // madvise requires a page aligned address
+        let page_size = page_size();
+        let start = data.as_ptr() as usize;
+        let aligned_start = start - start % page_size;
+        let len = data.len() + (start - aligned_start);
+        let result = unsafe { libc::madvise(aligned_start as *mut libc::c_void, len, advice) };
+        if result == 0 {
+            Ok(())
         } else {
-            false
+            Err(io::Error::last_os_error())
         }
     }
 
-    /// mark this entry as unlocked
-    fn unlock(&mut self) {
-        assert_eq!(UID_LOCKED, self.lock);
-        self.lock = UID_UNLOCKED;
-    }
-
-    /// true if this entry is unlocked
-    fn is_unlocked(&self) -> bool {
-        self.lock == UID_UNLOCKED
+    #[cfg(not(unix))]
+    fn advise(self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
     }
 }
 
-pub struct BucketStorage {
-    path: PathBuf,
-    mmap: MmapMut,
-    pub cell_size: u64,
-    pub capacity_pow2: u8,
-    pub count: Arc<AtomicU64>,
-    pub stats: Arc<BucketStats>,
-    pub max_search: MaxSearch,
-}
-
-#[derive(Debug)]
-pub enum BucketStorageError {
-    AlreadyAllocated,
-}
+/// size of the huge pages requested with `use_huge_pages`.
+/// Mappings smaller than this always use normal pages.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 
-impl Drop for BucketStorage {
-    fn drop(&mut self) {
-        let _ = remove_file(&self.path);
-    }
+/// The memory a bucket file is mapped into
+enum Mapping {
+    Mmap(MmapMut),
+    #[cfg(target_os = "linux")]
+    HugePages(HugePageMmap),
+    /// mapped without write access, see `BucketStorage::open_readonly`
+    ReadOnly(Mmap),
+    /// a region of a file shared with other storages, see `BucketStorageBuilder::build_in_arena`
+    Arena(ArenaRegion),
 }
 
-impl BucketStorage {
-    pub fn new_with_capacity(
-        drives: Arc<Vec<PathBuf>>,
-        num_elems: u64,
-        elem_size: u64,
-        capacity_pow2: u8,
-        max_search: MaxSearch,
-        stats: Arc<BucketStats>,
-        count: Arc<AtomicU64>,
-    ) -> Self {
-        let cell_size = elem_size * num_elems + Self::header_size() as u64;
-        let (mmap, path) = Self::new_map(&drives, cell_size as usize, capacity_pow2, &stats);
-        Self {
-            path,
-            mmap,
-            cell_size,
-            count,
-            capacity_pow2,
-            stats,
-            max_search,
+impl Mapping {
+    /// map the first 'len' bytes of 'file' read/write.
+    /// If `use_huge_pages`, try to back large mappings with huge pages,
+    /// falling back to normal pages if that fails.
+    /// If `numa_node` is set, try to bind the mapping to that node, continuing without if that fails.
+    fn map(file: &File, len: usize, config: &BucketStorageConfig) -> io::Result<Self> {
+        let mmap = Self::map_pages(file, len, config.use_huge_pages)?;
+        if let Some(node) = config.numa_node {
+            if let Err(err) = bind_to_numa_node(&mmap, node) {
+                log::warn!("unable to bind bucket mapping to numa node {node}: {err}");
+            }
         }
+        Ok(mmap)
     }
 
-    /// non-zero if there is a header allocated prior to each element to store the 'allocated' bit
-    fn header_size() -> usize {
-        match IS_ALLOCATED_FLAG_LOCATION {
-            IsAllocatedFlagLocation::InHeader => std::mem::size_of::<Header>(),
+    fn map_pages(file: &File, len: usize, use_huge_pages: bool) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if use_huge_pages && len >= HUGE_PAGE_SIZE {
+            if let Ok(mmap) = HugePageMmap::map(file, len) {
+                return Ok(Self::HugePages(mmap));
+            }
         }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (len, use_huge_pages);
+        Ok(Self::Mmap(unsafe { MmapMut::map_mut(file)? }))
     }
 
-    pub fn max_search(&self) -> u64 {
-        self.max_search as u64
+    fn map_read_only(file: &File) -> io::Result<Self> {
+        Ok(Self::ReadOnly(unsafe { Mmap::map(file)? }))
     }
 
-    pub fn new(
-        drives: Arc<Vec<PathBuf>>,
-        num_elems: u64,
-        elem_size: u64,
-        max_search: MaxSearch,
-        stats: Arc<BucketStats>,
-        count: Arc<AtomicU64>,
-    ) -> Self {
-        Self::new_with_capacity(
-            drives,
-            num_elems,
-            elem_size,
-            DEFAULT_CAPACITY_POW2,
-            max_search,
-            stats,
-            count,
-        )
+    fn is_read_only(&self) -> bool {
+        matches!(self, Self::ReadOnly(_))
     }
 
-    /// return ref to header of item 'ix' in mmapped file
-    fn header_ptr(&self, ix: u64) -> &Header {
-        self.header_mut_ptr(ix)
+    /// true if the file is shared with other storages, so it must not be removed or resized
+    fn is_arena(&self) -> bool {
+        matches!(self, Self::Arena(_))
     }
 
-    /// return ref to header of item 'ix' in mmapped file
-    #[allow(clippy::mut_from_ref)]
-    fn header_mut_ptr(&self, ix: u64) -> &mut Header {
-        assert_eq!(
-            IS_ALLOCATED_FLAG_LOCATION,
-            IsAllocatedFlagLocation::InHeader
-        );
-        let ix = (ix * self.cell_size) as usize;
-        let hdr_slice: &[u8] = &self.mmap[ix..ix + std::mem::size_of::<Header>()];
-        unsafe {
-            let hdr = hdr_slice.as_ptr() as *mut Header;
-            hdr.as_mut().unwrap()
+    /// extend the mapping to the first 'len' bytes of its file, which is at least that long,
+    /// without mapping it again. Returns false, leaving the mapping as it was, if it cannot be:
+    /// memmap2 cannot remap its mappings, and older kernels cannot mremap huge pages.
+    fn try_remap(&mut self, len: usize) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => match mmap.remap(len) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("unable to mremap bucket mapping to {len} bytes: {err}");
+                    false
+                }
+            },
+            _ => {
+                let _ = len;
+                false
+            }
         }
     }
 
-    /// true if the entry at index 'ix' is free (as opposed to being allocated)
-    pub fn is_free(&self, ix: u64) -> bool {
-        // note that the terminology in the implementation is locked or unlocked.
-        // but our api is allocate/free
-        match IS_ALLOCATED_FLAG_LOCATION {
-            IsAllocatedFlagLocation::InHeader => self.header_ptr(ix).is_unlocked(),
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            Self::Mmap(mmap) => mmap.flush(),
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => mmap.flush(libc::MS_SYNC),
+            // nothing can be dirty
+            Self::ReadOnly(_) => Ok(()),
+            Self::Arena(region) => region.flush(),
         }
     }
 
-    fn try_lock(&mut self, ix: u64) -> bool {
-        match IS_ALLOCATED_FLAG_LOCATION {
-            IsAllocatedFlagLocation::InHeader => self.header_mut_ptr(ix).try_lock(),
+    /// write the file prologue to disk, blocking until it is durable
+    fn flush_prologue(&self) -> io::Result<()> {
+        match self {
+            Self::Mmap(mmap) => mmap.flush_range(0, PROLOGUE_SIZE),
+            // the whole mapping, msync of part of a huge page is not worth the bookkeeping
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => mmap.flush(libc::MS_SYNC),
+            Self::ReadOnly(_) => Ok(()),
+            Self::Arena(region) => region.flush(),
         }
     }
 
-    /// 'is_resizing' true if caller is resizing the index (so don't increment count)
-    /// 'is_resizing' false if caller is adding an item to the index (so increment count)
-    pub fn allocate(&mut self, ix: u64, is_resizing: bool) -> Result<(), BucketStorageError> {
-        assert!(ix < self.capacity(), "allocate: bad index size");
-        let mut e = Err(BucketStorageError::AlreadyAllocated);
-        //debug!("ALLOC {} {}", ix, uid);
-        if self.try_lock(ix) {
-            e = Ok(());
-            if !is_resizing {
-                self.count.fetch_add(1, Ordering::Relaxed);
-            }
+    fn flush_async(&self) -> io::Result<()> {
+        match self {
+            Self::Mmap(mmap) => mmap.flush_async(),
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => mmap.flush(libc::MS_ASYNC),
+            Self::ReadOnly(_) => Ok(()),
+            Self::Arena(region) => region.flush_async(),
         }
-        e
     }
+}
 
-    pub fn free(&mut self, ix: u64) {
-        assert!(ix < self.capacity(), "bad index size");
-        match IS_ALLOCATED_FLAG_LOCATION {
-            IsAllocatedFlagLocation::InHeader => {
-                self.header_mut_ptr(ix).unlock();
-            }
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mmap(mmap) => mmap,
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => mmap.as_slice(),
+            Self::ReadOnly(mmap) => mmap,
+            Self::Arena(region) => region.as_slice(),
         }
-        self.count.fetch_sub(1, Ordering::Relaxed);
     }
+}
 
-    pub fn get<T: Sized>(&self, ix: u64) -> &T {
-        let start = self.get_start_offset(ix);
-        let end = start + std::mem::size_of::<T>();
-        let item_slice: &[u8] = &self.mmap[start..end];
-        unsafe {
-            let item = item_slice.as_ptr() as *const T;
-            &*item
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Mmap(mmap) => mmap,
+            #[cfg(target_os = "linux")]
+            Self::HugePages(mmap) => mmap.as_mut_slice(),
+            Self::ReadOnly(_) => panic!("bucket storage is read-only"),
+            Self::Arena(region) => region.as_mut_slice(),
         }
     }
+}
 
-    pub fn get_empty_cell_slice<T: Sized + 'static>() -> &'static [T] {
-        &[]
+/// set the memory policy of the pages of 'data' to allocate only from numa node 'node'
+/// and move any pages already allocated elsewhere, see mbind(2)
+#[cfg(target_os = "linux")]
+fn bind_to_numa_node(data: &[u8], node: u32) -> io::Result<()> {
+    // from numaif.h
+    const MPOL_BIND: libc::c_int = 2;
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+    // the largest number of nodes the kernel supports, CONFIG_NODES_SHIFT is at most 10
+    const MAX_NUMNODES: u32 = 1 << 10;
+    if data.is_empty() {
+        return Ok(());
     }
-
-    fn get_start_offset(&self, ix: u64) -> usize {
-        assert!(ix < self.capacity(), "bad index size");
-        let ix = self.cell_size * ix;
-        ix as usize + Self::header_size()
+    if node >= MAX_NUMNODES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("numa node {node} is out of range"),
+        ));
+    }
+    const BITS: usize = u64::BITS as usize;
+    let node = node as usize;
+    let mut nodemask = vec![0u64; node / BITS + 1];
+    nodemask[node / BITS] |= 1 << (node % BITS);
+    // mbind requires a page aligned address
+    let page_size = page_size();
+    let start = data.as_ptr() as usize;
+    let aligned_start = start - start % page_size;
+    let len = data.len() + (start - aligned_start);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            aligned_start,
+            len,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            // the kernel reads one bit less than 'maxnode'
+            nodemask.len() * BITS + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
     }
+}
 
-    pub fn get_cell_slice<T: Sized>(&self, ix: u64, len: u64) -> &[T] {
-        let start = self.get_start_offset(ix);
-        let end = start + std::mem::size_of::<T>() * len as usize;
-        //debug!("GET slice {} {}", start, end);
-        let item_slice: &[u8] = &self.mmap[start..end];
-        unsafe {
-            let item = item_slice.as_ptr() as *const T;
-            std::slice::from_raw_parts(item, len as usize)
-        }
+#[cfg(not(target_os = "linux"))]
+fn bind_to_numa_node(_data: &[u8], _node: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// size of the pages mappings are made of, see sysconf(3)
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// `WARMUP_STRIDE`, the smallest page size in common use, where the page size is not queried
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    WARMUP_STRIDE
+}
+
+/// lock the pages of 'data' in memory, see mlock(2), so they are not evicted.
+/// This is best effort: a failure, usually because of `RLIMIT_MEMLOCK`, is logged and counted in
+/// `mlock_failures`.
+#[cfg(unix)]
+fn mlock_best_effort(data: &[u8], stats: &BucketStats) {
+    if data.is_empty() {
+        return;
+    }
+    if unsafe { libc::mlock(data.as_ptr() as *const libc::c_void, data.len()) } != 0 {
+        let err = io::Error::last_os_error();
+        log::warn!(
+            "unable to mlock {} bytes of bucket mapping: {err}",
+            data.len()
+        );
+        stats.mlock_failures.fetch_add(1, Ordering::Relaxed);
     }
+}
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn get_mut<T: Sized>(&self, ix: u64) -> &mut T {
-        let start = self.get_start_offset(ix);
-        let end = start + std::mem::size_of::<T>();
-        let item_slice: &[u8] = &self.mmap[start..end];
-        unsafe {
-            let item = item_slice.as_ptr() as *mut T;
-            &mut *item
-        }
+#[cfg(not(unix))]
+fn mlock_best_effort(_data: &[u8], _stats: &BucketStats) {}
+
+/// undo `mlock_best_effort`
+#[cfg(unix)]
+fn munlock(data: &[u8]) {
+    if !data.is_empty() {
+        unsafe { libc::munlock(data.as_ptr() as *const libc::c_void, data.len()) };
     }
+}
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn get_mut_cell_slice<T: Sized>(&self, ix: u64, len: u64) -> &mut [T] {
-        let start = self.get_start_offset(ix);
-        let end = start + std::mem::size_of::<T>() * len as usize;
-        //debug!("GET mut slice {} {}", start, end);
-        let item_slice: &[u8] = &self.mmap[start..end];
-        unsafe {
-            let item = item_slice.as_ptr() as *mut T;
-            std::slice::from_raw_parts_mut(item, len as usize)
-        }
+#[cfg(not(unix))]
+fn munlock(_data: &[u8]) {}
+
+#[cfg(unix)]
+fn free_bytes_on(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    let stat = unsafe { stat.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
 
-    fn new_map(
-        drives: &[PathBuf],
-        cell_size: usize,
-        capacity_pow2: u8,
-        stats: &BucketStats,
-    ) -> (MmapMut, PathBuf) {
-        let mut measure_new_file = Measure::start("measure_new_file");
-        let capacity = 1u64 << capacity_pow2;
-        let r = thread_rng().gen_range(0, drives.len());
-        let drive = &drives[r];
-        let pos = format!("{}", thread_rng().gen_range(0, u128::MAX),);
-        let file = drive.join(pos);
-        let mut data = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file.clone())
-            .map_err(|e| {
-                panic!(
-                    "Unable to create data file {} in current dir({:?}): {:?}",
-                    file.display(),
-                    std::env::current_dir(),
-                    e
-                );
-            })
-            .unwrap();
+#[cfg(not(unix))]
+fn free_bytes_on(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is only available on unix",
+    ))
+}
 
-        // Theoretical performance optimization: write a zero to the end of
-        // the file so that we won't have to resize it later, which may be
-        // expensive.
-        //debug!("GROWING file {}", capacity * cell_size as u64);
-        data.seek(SeekFrom::Start(capacity * cell_size as u64 - 1))
-            .unwrap();
-        data.write_all(&[0]).unwrap();
-        data.rewind().unwrap();
-        measure_new_file.stop();
-        let mut measure_flush = Measure::start("measure_flush");
-        data.flush().unwrap(); // can we skip this?
-        measure_flush.stop();
-        let mut measure_mmap = Measure::start("measure_mmap");
-        let res = (unsafe { MmapMut::map_mut(&data).unwrap() }, file);
-        measure_mmap.stop();
-        stats
-            .new_file_us
-            .fetch_add(measure_new_file.as_us(), Ordering::Relaxed);
-        stats
-            .flush_file_us
-            .fetch_add(measure_flush.as_us(), Ordering::Relaxed);
-        stats
-            .mmap_us
-            .fetch_add(measure_mmap.as_us(), Ordering::Relaxed);
-        res
+/// true if 'err' is ENOSPC, the drive has no space left.
+/// `ErrorKind::StorageFull` is not stable yet, so the raw error is checked.
+#[cfg(unix)]
+fn is_out_of_space(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOSPC)
+}
+
+#[cfg(not(unix))]
+fn is_out_of_space(_err: &io::Error) -> bool {
+    false
+}
+
+/// allocate the blocks of the first 'len' bytes of 'file', see fallocate(2), so that a drive
+/// without space fails here with ENOSPC rather than with SIGBUS when a page of a mapping of the
+/// file is first written. Files on filesystems that cannot preallocate (EOPNOTSUPP) stay sparse.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        Ok(())
+    } else {
+        Err(err)
     }
+}
 
-    /// copy contents from 'old_bucket' to 'self'
-    /// This is used by data buckets
-    fn copy_contents(&mut self, old_bucket: &Self) {
-        let mut m = Measure::start("grow");
-        let old_cap = old_bucket.capacity();
-        let old_map = &old_bucket.mmap;
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
 
-        let increment = self.capacity_pow2 - old_bucket.capacity_pow2;
-        let index_grow = 1 << increment;
-        (0..old_cap as usize).for_each(|i| {
-            if !old_bucket.is_free(i as u64) {
-                match IS_ALLOCATED_FLAG_LOCATION {
-                    IsAllocatedFlagLocation::InHeader => {
-                        // nothing to do when bit is in header
-                    }
-                }
-                let old_ix = i * old_bucket.cell_size as usize;
-                let new_ix = old_ix * index_grow;
-                let dst_slice: &[u8] = &self.mmap[new_ix..new_ix + old_bucket.cell_size as usize];
-                let src_slice: &[u8] = &old_map[old_ix..old_ix + old_bucket.cell_size as usize];
+/// copy the file at 'from' to a new file at 'to'.
+/// On Linux, the copy shares the blocks of 'from' (FICLONE, see ioctl_ficlone(2)) if the
+/// filesystem supports it, and the data is only copied if it doesn't.
+fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // _IOW(0x94, 9, int) from linux/fs.h
+        const FICLONE: libc::c_ulong = 0x4004_9409;
+        let source = File::open(from)?;
+        let clone = OpenOptions::new().write(true).create_new(true).open(to)?;
+        if unsafe { libc::ioctl(clone.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } == 0 {
+            return Ok(());
+        }
+        // the filesystem cannot share blocks (EOPNOTSUPP, EXDEV, ...), copy the data instead
+    }
+    std::fs::copy(from, to).map(|_| ()).map_err(|err| {
+        let _ = remove_file(to);
+        err
+    })
+}
 
-                unsafe {
-                    let dst = dst_slice.as_ptr() as *mut u8;
-                    let src = src_slice.as_ptr() as *const u8;
-                    std::ptr::copy_nonoverlapping(src, dst, old_bucket.cell_size as usize);
-                };
+/// call 'map' until it succeeds, fails with an error other than `ErrorKind::OutOfMemory`
+/// (ENOMEM on unix), or has been retried `MMAP_ENOMEM_RETRIES` times. The kernel may be able to
+/// reclaim memory for a mapping that failed with ENOMEM moments earlier. Retries are counted in
+/// `mmap_retries`.
+fn retry_on_enomem<T>(
+    stats: &BucketStats,
+    mut map: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut backoff = MMAP_RETRY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match map() {
+            Err(err)
+                if err.kind() == io::ErrorKind::OutOfMemory && retries < MMAP_ENOMEM_RETRIES =>
+            {
+                log::warn!("mmap failed with {err}, retrying in {backoff:?}");
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+                stats.mmap_retries.fetch_add(1, Ordering::Relaxed);
             }
-        });
-        m.stop();
-        // resized so update total file size
-        self.stats.resizes.fetch_add(1, Ordering::Relaxed);
-        self.stats.resize_us.fetch_add(m.as_us(), Ordering::Relaxed);
+            result => return result,
+        }
+    }
+}
+
+/// A shared file mapping made with `MAP_HUGETLB`, which memmap2 does not support.
+/// The kernel only allows this for files on a hugetlbfs mount.
+#[cfg(target_os = "linux")]
+struct HugePageMmap {
+    ptr: *mut u8,
+    /// number of bytes of the file that are mapped
+    len: usize,
+    /// 'len' rounded up to whole huge pages, as required by munmap
+    mapped_len: usize,
+}
+
+// the mapping is plain memory, owned by this struct, like memmap2::MmapMut
+#[cfg(target_os = "linux")]
+unsafe impl Send for HugePageMmap {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for HugePageMmap {}
+
+#[cfg(target_os = "linux")]
+impl HugePageMmap {
+    fn map(file: &File, len: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        let mapped_len = (len + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_HUGETLB,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+            mapped_len,
+        })
     }
 
-    pub fn update_max_size(&self) {
-        self.stats.update_max_size(self.capacity());
+    /// grow or shrink the mapping to the first 'len' bytes of the file with mremap(2), which may
+    /// move it. The pages already mapped are kept, and so are their lock and memory policy.
+    fn remap(&mut self, len: usize) -> io::Result<()> {
+        let mapped_len = (len + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        let ptr = unsafe {
+            libc::mremap(
+                self.ptr as *mut libc::c_void,
+                self.mapped_len,
+                mapped_len,
+                libc::MREMAP_MAYMOVE,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        self.ptr = ptr as *mut u8;
+        self.len = len;
+        self.mapped_len = mapped_len;
+        Ok(())
     }
 
-    /// allocate a new bucket, copying data from 'bucket'
-    pub fn new_resized(
-        drives: &Arc<Vec<PathBuf>>,
-        max_search: MaxSearch,
-        bucket: Option<&Self>,
-        capacity_pow_2: u8,
-        num_elems: u64,
-        elem_size: u64,
-        stats: &Arc<BucketStats>,
-    ) -> Self {
-        let mut new_bucket = Self::new_with_capacity(
-            Arc::clone(drives),
-            num_elems,
-            elem_size,
-            capacity_pow_2,
-            max_search,
-            Arc::clone(stats),
-            bucket
-                .map(|bucket| Arc::clone(&bucket.count))
-                .unwrap_or_default(),
-        );
-        if let Some(bucket) = bucket {
-            new_bucket.copy_contents(bucket);
+    fn flush(&self, flags: libc::c_int) -> io::Result<()> {
+        if unsafe { libc::msync(self.ptr as *mut libc::c_void, self.len, flags) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
         }
-        new_bucket.update_max_size();
-        new_bucket
     }
 
-    /// Return the number of bytes currently allocated
-    pub(crate) fn capacity_bytes(&self) -> u64 {
-        self.capacity() * self.cell_size
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
     }
 
-    /// Return the number of cells currently allocated
-    pub fn capacity(&self) -> u64 {
-        1 << self.capacity_pow2
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 }
 
-#[cfg(test)]
-mod test {
-    use {super::*, tempfile::tempdir};
+#[cfg(target_os = "linux")]
+impl Drop for HugePageMmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.mapped_len);
+        }
+    }
+}
+
+/// When a `BucketStorage` flushes its mapping to disk, in addition to explicit calls to `flush`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// leave flushing to the kernel
+    #[default]
+    Never,
+    /// flush when the storage is dropped, if its file is kept on disk
+    OnDrop,
+    /// start a `flush_async` after every n calls to allocate, free or get a mutable cell
+    EveryNWrites(u64),
+}
+
+/// Memory ordering of the updates to `BucketStorage::count` made by allocating and freeing cells
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CountOrdering {
+    /// cheapest. Another thread reading `count` may see a stale value, and seeing an allocation
+    /// counted does not mean it sees the writes made before the allocation.
+    #[default]
+    Relaxed,
+    /// updates release, so a `len_acquire` that sees an allocation counted also sees every write
+    /// the allocating thread made before it. Costs a barrier on weakly ordered cpus.
+    AcqRel,
+}
+
+impl CountOrdering {
+    fn update(self) -> Ordering {
+        match self {
+            Self::Relaxed => Ordering::Relaxed,
+            Self::AcqRel => Ordering::AcqRel,
+        }
+    }
+
+    fn store(self) -> Ordering {
+        match self {
+            Self::Relaxed => Ordering::Relaxed,
+            Self::AcqRel => Ordering::Release,
+        }
+    }
+}
+
+/// How a `BucketStorage` picks the drive to create a new file on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DriveSelection {
+    /// a random drive for every file
+    #[default]
+    Random,
+    /// the next drive after the one the previous file was created on, counted by
+    /// `BucketStats::next_drive`. Storages sharing stats spread their files evenly across drives.
+    RoundRobin,
+}
+
+/// Returned by `BucketStorage::allocate_or_hint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeHint {
+    /// occupancy is at or below `BucketStorageConfig::resize_threshold`
+    None,
+    /// occupancy is above `BucketStorageConfig::resize_threshold`, the caller should resize
+    NeedsResize,
+}
+
+/// How `BucketStorage::open` maps an existing file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenMode {
+    ReadWrite,
+    /// mapped without write access, see `open_readonly`
+    ReadOnly,
+    /// writable, without checking the contents checksum, see `open_existing_and_repair`
+    Repair,
+}
+
+/// Optional behavior of a `BucketStorage`.
+/// The default matches the behavior of `BucketStorage::new`.
+#[derive(Debug, Clone)]
+pub struct BucketStorageConfig {
+    /// store a crc32 of each cell after its header so corruption can be detected with `verify`
+    pub checksums: bool,
+    /// `sync_all` a newly created file before mapping it, so its full size is durable on disk
+    pub sync_on_create: bool,
+    /// access pattern hint for the mapping
+    pub mmap_advice: MmapAdvice,
+    /// back mappings of at least 2MB with huge pages (`MAP_HUGETLB`) when the kernel allows it,
+    /// otherwise use normal pages. Ignored on platforms other than linux.
+    pub use_huge_pages: bool,
+    /// zero the contents of a cell when it is freed, so stale data cannot be read from it
+    pub scrub_on_free: bool,
+    /// file names are '{prefix}.{random}' instead of '{random}', to tell which bucket a file belongs to
+    pub file_name_prefix: Option<String>,
+    /// when to flush the mapping to disk
+    pub flush_policy: FlushPolicy,
+    /// allocate the pages of the mapping on this numa node, if the kernel allows it.
+    /// Ignored on platforms other than linux.
+    pub numa_node: Option<u32>,
+    /// refuse to create or grow a file larger than this many bytes, including the prologue
+    pub max_file_bytes: Option<u64>,
+    /// time touching the cell of every nth call to get a mutable cell, to measure page faults.
+    /// 0 disables sampling.
+    pub write_sample_rate: u64,
+    /// where the 'allocated' flag of each cell is stored
+    pub allocated_flag_location: IsAllocatedFlagLocation,
+    /// memory ordering of updates to `count`
+    pub count_ordering: CountOrdering,
+    /// number of powers of two to grow by when resizing, see `next_capacity_pow2`. 0 is treated as 1.
+    /// Larger steps resize (and so `copy_contents`) less often for quickly growing storages.
+    pub grow_step_pow2: u8,
+    /// how the drive for a new file is picked
+    pub drive_selection: DriveSelection,
+    /// occupancy above which `allocate_or_hint` returns `ResizeHint::NeedsResize`
+    pub resize_threshold: f64,
+    /// `mlock` the mapping once it is mapped, so lookups never wait for pages to be read back in,
+    /// and `munlock` it when dropped. Best effort, see `BucketStats::mlock_failures`.
+    /// Ignored on platforms other than unix.
+    pub mlock_on_open: bool,
+}
+
+impl Default for BucketStorageConfig {
+    fn default() -> Self {
+        Self {
+            checksums: false,
+            sync_on_create: false,
+            mmap_advice: MmapAdvice::default(),
+            use_huge_pages: false,
+            scrub_on_free: false,
+            file_name_prefix: None,
+            flush_policy: FlushPolicy::default(),
+            numa_node: None,
+            max_file_bytes: None,
+            write_sample_rate: 0,
+            allocated_flag_location: IsAllocatedFlagLocation::default(),
+            count_ordering: CountOrdering::default(),
+            grow_step_pow2: 1,
+            drive_selection: DriveSelection::default(),
+            resize_threshold: 0.75,
+            mlock_on_open: false,
+        }
+    }
+}
+
+/// Number of bytes reserved at the start of every bucket file, before the first cell.
+/// This is a multiple of 8 so that cell headers stay aligned.
+const PROLOGUE_SIZE: usize = 64;
+/// identifies a file as a bucket storage file
+const PROLOGUE_MAGIC: u64 = u64::from_le_bytes(*b"SOLBUCKT");
+/// version of the on-disk layout described by the prologue
+const FORMAT_VERSION: u64 = 1;
+/// layout of the `Header` at the start of each cell, stored in the prologue so the header can change
+/// without misreading existing files. Version 1 is a single u64 lock word, holding the uid the
+/// cell is allocated with and the `COMMITTED` bit.
+const HEADER_VERSION: u64 = 1;
+/// offset in the prologue of the header version. Files written before the header version was
+/// stored have 0 there, and were written with version 1.
+const HEADER_VERSION_OFFSET: usize = 40;
+/// offset in the prologue, after the `FilePrologue` fields, of the crc32 of the rest of the file
+/// as of the last time the file was closed
+const CONTENTS_CHECKSUM_OFFSET: usize = 32;
+/// set in the stored contents checksum when it holds a crc32.
+/// It is cleared, on disk, when the file is opened for writing and only set again by a clean
+/// close, so files that were never closed, or not cleanly since they were last opened for writing,
+/// have 0 there and are not checked when opened.
+const CONTENTS_CHECKSUM_RECORDED: u64 = 1 << 32;
+
+/// Describes the layout of a bucket file.
+/// Stored little-endian in the first `PROLOGUE_SIZE` bytes of the file.
+#[derive(Debug, PartialEq, Eq)]
+struct FilePrologue {
+    magic: u64,
+    version: u64,
+    cell_size: u64,
+    capacity_pow2: u64,
+    /// stored at `HEADER_VERSION_OFFSET`
+    header_version: u64,
+}
+
+impl FilePrologue {
+    fn new(cell_size: u64, capacity_pow2: u8) -> Self {
+        Self {
+            magic: PROLOGUE_MAGIC,
+            version: FORMAT_VERSION,
+            cell_size,
+            capacity_pow2: capacity_pow2 as u64,
+            header_version: HEADER_VERSION,
+        }
+    }
+
+    fn write(&self, data: &mut [u8]) {
+        let fields = [self.magic, self.version, self.cell_size, self.capacity_pow2];
+        for (field, bytes) in fields.iter().zip(data.chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        data[HEADER_VERSION_OFFSET..HEADER_VERSION_OFFSET + 8]
+            .copy_from_slice(&self.header_version.to_le_bytes());
+    }
+
+    fn read(data: &[u8]) -> Self {
+        let mut fields = data
+            .chunks_exact(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        let header_version = u64::from_le_bytes(
+            data[HEADER_VERSION_OFFSET..HEADER_VERSION_OFFSET + 8]
+                .try_into()
+                .unwrap(),
+        );
+        Self {
+            magic: next(),
+            version: next(),
+            cell_size: next(),
+            capacity_pow2: next(),
+            header_version: match header_version {
+                0 => 1,
+                header_version => header_version,
+            },
+        }
+    }
+
+    /// check that the prologue at the start of 'data' describes the expected layout
+    fn check(data: &[u8], cell_size: u64, capacity_pow2: u8) -> Result<(), BucketStorageError> {
+        if data.len() < PROLOGUE_SIZE {
+            return Err(BucketStorageError::BadHeader);
+        }
+        let prologue = Self::read(&data[..PROLOGUE_SIZE]);
+        if prologue.magic == PROLOGUE_MAGIC
+            && BucketStorage::header_layout_size(prologue.header_version).is_none()
+        {
+            return Err(BucketStorageError::UnsupportedHeaderVersion {
+                header_version: prologue.header_version,
+            });
+        }
+        if prologue != Self::new(cell_size, capacity_pow2) {
+            return Err(BucketStorageError::BadHeader);
+        }
+        Ok(())
+    }
+
+    /// crc32 of everything in the mapped file 'data' after the prologue
+    fn contents_checksum(data: &[u8]) -> u32 {
+        crc32fast::hash(&data[PROLOGUE_SIZE..])
+    }
+
+    /// the contents checksum stored in the prologue of 'data', if one was recorded
+    fn read_contents_checksum(data: &[u8]) -> Option<u32> {
+        let bytes = &data[CONTENTS_CHECKSUM_OFFSET..CONTENTS_CHECKSUM_OFFSET + 8];
+        let stored = u64::from_le_bytes(bytes.try_into().unwrap());
+        (stored & CONTENTS_CHECKSUM_RECORDED != 0).then_some(stored as u32)
+    }
+
+    /// store the current contents checksum of 'data' in its prologue
+    fn write_contents_checksum(data: &mut [u8]) {
+        let stored = CONTENTS_CHECKSUM_RECORDED | Self::contents_checksum(data) as u64;
+        data[CONTENTS_CHECKSUM_OFFSET..CONTENTS_CHECKSUM_OFFSET + 8]
+            .copy_from_slice(&stored.to_le_bytes());
+    }
+
+    /// forget the contents checksum stored in the prologue of 'data', before its contents change
+    fn clear_contents_checksum(data: &mut [u8]) {
+        data[CONTENTS_CHECKSUM_OFFSET..CONTENTS_CHECKSUM_OFFSET + 8].fill(0);
+    }
+}
+
+/// A Header UID of 0 indicates that the header is unlocked
+const UID_UNLOCKED: Uid = 0;
+/// uid in maps is 1 or 0, where 0 is empty, 1 is in-use
+const UID_LOCKED: Uid = 1;
+/// set in the lock word, along with the uid, once the contents of an allocated cell are written.
+/// See `BucketStorage::commit`.
+const COMMITTED: u64 = 1 << 63;
+
+/// u64 for purposes of 8 byte alignment
+/// We only need 1 bit of this, unless the owner of the cell is stored by `allocate_with_uid`.
+type Uid = u64;
+
+/// The lock word is accessed atomically in place in the mmapped file,
+/// so concurrent allocations of the same cell cannot both succeed.
+#[repr(C)]
+struct Header {
+    lock: AtomicU64,
+}
+
+impl Header {
+    /// try to lock this entry with `UID_LOCKED`
+    /// return true if it could be locked
+    fn try_lock(&self) -> bool {
+        self.try_lock_uid(UID_LOCKED)
+    }
+
+    /// try to lock this entry with 'uid'
+    /// return true if it could be locked
+    fn try_lock_uid(&self, uid: Uid) -> bool {
+        self.lock
+            .compare_exchange(UID_UNLOCKED, uid, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// mark this entry as unlocked
+    fn unlock(&self) {
+        assert_eq!(UID_LOCKED, self.lock.load(Ordering::Relaxed) & !COMMITTED);
+        self.lock.store(UID_UNLOCKED, Ordering::Release);
+    }
+
+    /// mark this entry as unlocked if it is locked with 'uid', committed or not
+    /// otherwise return the uid it is locked with
+    fn unlock_uid(&self, uid: Uid) -> Result<(), Uid> {
+        let current = self.lock.load(Ordering::Relaxed);
+        if current & !COMMITTED != uid {
+            return Err(current & !COMMITTED);
+        }
+        self.lock
+            .compare_exchange(current, UID_UNLOCKED, Ordering::Release, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|actual| actual & !COMMITTED)
+    }
+
+    /// the uid this entry is locked with
+    fn uid(&self) -> Uid {
+        self.lock.load(Ordering::Acquire) & !COMMITTED
+    }
+
+    /// mark this locked entry as committed
+    fn commit(&self) {
+        self.lock.fetch_or(COMMITTED, Ordering::Release);
+    }
+
+    fn is_committed(&self) -> bool {
+        self.lock.load(Ordering::Acquire) & COMMITTED != 0
+    }
+
+    /// true if this entry is unlocked
+    fn is_unlocked(&self) -> bool {
+        self.lock.load(Ordering::Acquire) == UID_UNLOCKED
+    }
+}
+
+/// Cells of a file mapped into memory, allocated and freed by index.
+///
+/// Thread safety: writing to a cell (`get_mut`, `get_mut_cell_slice`, `get_bytes_mut`) and
+/// allocating or freeing cells needs `&mut self`, so a storage shared between threads by reference
+/// can only be read. Use `SyncBucketStorage` to share a storage that is written by several threads.
+/// The methods that change a storage through `&self` are either atomic (`cas_u64`, `count`),
+/// `unsafe` (`lock_cell_mut`) or hand out a raw pointer that is unsafe to write through
+/// (`payload_ptr_mut`).
+///
+/// ```compile_fail
+/// use solana_bucket_map::BucketStorage;
+///
+/// // a shared reference cannot write a cell
+/// fn write_shared(storage: &BucketStorage) {
+///     *storage.get_mut::<u64>(0) = 1;
+/// }
+/// ```
+///
+/// ```
+/// use {
+///     solana_bucket_map::{BucketStorage, SyncBucketStorage},
+///     std::sync::Arc,
+/// };
+///
+/// let drives = Arc::new(vec![std::env::temp_dir()]);
+/// let storage = SyncBucketStorage::new(BucketStorage::new(
+///     drives,
+///     1,
+///     std::mem::size_of::<u64>() as u64,
+///     1,
+///     Arc::default(),
+///     Arc::default(),
+/// ));
+/// let writer = storage.clone();
+/// std::thread::spawn(move || {
+///     let mut storage = writer.write();
+///     storage.allocate(0, false).unwrap();
+///     *storage.get_mut::<u64>(0) = 1;
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(*storage.read().get::<u64>(0), 1);
+/// ```
+pub struct BucketStorage {
+    /// None for an anonymous mapping, see `BucketStorageBuilder::build_anonymous`
+    path: Option<PathBuf>,
+    /// drives to create the files of resized copies of this storage on, see `new_resized`.
+    /// Empty for an anonymous storage.
+    drives: Arc<Vec<PathBuf>>,
+    mmap: Mapping,
+    pub cell_size: u64,
+    capacity_pow2: u8,
+    /// number of allocated cells, prefer `len`
+    pub count: Arc<AtomicU64>,
+    pub stats: Arc<BucketStats>,
+    pub max_search: MaxSearch,
+    /// true if the backing file should be left on disk when this storage is dropped
+    pub persist_on_drop: bool,
+    /// true if the bytes of this storage were added to `stats.bytes_allocated`, so removing them
+    /// adds to `bytes_freed`. False for a file that was opened rather than created.
+    bytes_counted: bool,
+    /// called with the old and new capacity_pow2 whenever this storage grows.
+    /// Carried over to the storage created by `new_resized`.
+    pub resize_hook: Option<ResizeHook>,
+    config: BucketStorageConfig,
+    /// mutating operations since the last flush started by `FlushPolicy::EveryNWrites`
+    writes_since_flush: AtomicU64,
+    /// calls to get a mutable cell, for `write_sample_rate`
+    mutable_accesses: AtomicU64,
+    /// true once `close` or `remove_file_now` has handled the file, so `Drop` has nothing left
+    /// to do. No cells can be allocated after `remove_file_now`.
+    closed: bool,
+    /// indexes of the cells currently held by a `CellGuard`, only tracked with debug assertions
+    borrowed_cells: Mutex<HashSet<u64>>,
+    /// where the next `try_allocate_any` starts scanning, just past the cell it last allocated
+    allocate_any_hint: u64,
+}
+
+/// see `BucketStorage::resize_hook`
+pub type ResizeHook = Arc<dyn Fn(u8, u8) + Send + Sync>;
+
+/// the mapping and file of a storage, see `BucketStorage::into_raw_parts`
+pub struct RawParts {
+    pub mmap: MmapMut,
+    pub path: PathBuf,
+    pub cell_size: u64,
+    pub capacity_pow2: u8,
+}
+
+#[derive(Debug)]
+pub enum BucketStorageError {
+    AlreadyAllocated,
+    /// the file prologue does not describe the expected layout
+    BadHeader,
+    IndexOutOfBounds {
+        ix: u64,
+        capacity: u64,
+    },
+    /// no free cell within max_search cells of the starting index
+    NoFreeCell,
+    /// the arena has no free region large enough for the storage
+    ArenaFull,
+    /// 'capacity_pow2' is larger than `MAX_CAPACITY_POW2`, or the file would be larger than
+    /// `BucketStorageConfig::max_file_bytes`
+    CapacityTooLarge {
+        capacity_pow2: u8,
+    },
+    /// `UID_UNLOCKED`, and uids with the high bit (`COMMITTED`) set, cannot be used as an owner uid
+    ReservedUid,
+    /// the storage was opened with `open_readonly`
+    ReadOnly,
+    /// uids are stored in cell headers, which `IsAllocatedFlagLocation::Bitmap` storages do not have
+    UidsUnsupported,
+    /// the cell is not allocated by 'uid', 'owner' is the uid it is allocated by or `UID_UNLOCKED`
+    UidMismatch {
+        uid: u64,
+        owner: u64,
+    },
+    /// the file was written with a layout of the cell headers this code does not know
+    UnsupportedHeaderVersion {
+        header_version: u64,
+    },
+    /// the contents of the file do not match the checksum recorded when it was last closed,
+    /// so it was modified or only partially written since
+    ChecksumMismatch,
+    /// the file was removed with `remove_file_now`, so the storage holds no cells
+    FileRemoved,
+    /// the storages lay out their cells differently: in cell size, checksums, or where the
+    /// 'allocated' flag is stored
+    LayoutMismatch,
+    Io(io::Error),
+}
+
+impl From<io::Error> for BucketStorageError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl Drop for BucketStorage {
+    fn drop(&mut self) {
+        if self.config.mlock_on_open {
+            munlock(&self.mmap);
+        }
+        if self.closed {
+            return;
+        }
+        if self.persist_on_drop {
+            self.record_contents_checksum();
+        }
+        if self.persist_on_drop && self.config.flush_policy == FlushPolicy::OnDrop {
+            if let Err(err) = self.mmap.flush() {
+                log::error!("failed to flush bucket file {}: {err}", self.name());
+            }
+        }
+        // an arena region is returned to its arena when the mapping is dropped
+        if !self.persist_on_drop && !self.mmap.is_arena() {
+            // an anonymous mapping has no file, its memory is released with the mapping
+            let freed = match &self.path {
+                Some(path) => remove_file(path).is_ok(),
+                None => true,
+            };
+            if freed {
+                self.count_bytes_freed();
+            }
+        }
+    }
+}
+
+impl BucketStorage {
+    pub fn new_with_capacity(
+        drives: Arc<Vec<PathBuf>>,
+        num_elems: u64,
+        elem_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+    ) -> Self {
+        Self::try_new_with_capacity(
+            drives,
+            num_elems,
+            elem_size,
+            capacity_pow2,
+            max_search,
+            stats,
+            count,
+        )
+        .unwrap()
+    }
+
+    /// same as `new_with_capacity`, but returns an error instead of panicking if the backing file cannot be created or mapped
+    pub fn try_new_with_capacity(
+        drives: Arc<Vec<PathBuf>>,
+        num_elems: u64,
+        elem_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+    ) -> Result<Self, BucketStorageError> {
+        BucketStorageBuilder::default()
+            .drives(drives)
+            .elem(num_elems, elem_size)
+            .capacity_pow2(capacity_pow2)
+            .max_search(max_search)
+            .stats(stats)
+            .count(count)
+            .build()
+    }
+
+    /// create a file on 'drive' sized for 2^'capacity_pow2' cells of 'cell_size', without mapping it,
+    /// to find out whether the drive has room for it.
+    /// The file is left on disk for `open_existing` with the default config to adopt,
+    /// or for the caller to remove. On error, no file is left behind.
+    pub fn try_reserve_file(
+        drive: &Path,
+        cell_size: u64,
+        capacity_pow2: u8,
+    ) -> io::Result<PathBuf> {
+        let config = BucketStorageConfig::default();
+        if capacity_pow2 > MAX_CAPACITY_POW2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("capacity_pow2 {capacity_pow2} is larger than {MAX_CAPACITY_POW2}"),
+            ));
+        }
+        let len = (1u64 << capacity_pow2)
+            .checked_mul(cell_size)
+            .and_then(|bytes| {
+                bytes.checked_add(Self::cells_offset_for(capacity_pow2, &config) as u64)
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file size overflows"))?;
+        let random = thread_rng().gen_range(0, u128::MAX);
+        let path = drive.join(format!("{random}"));
+        let mut data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let mut reserve = || {
+            data.set_len(len)?;
+            let mut prologue = [0u8; PROLOGUE_SIZE];
+            FilePrologue::new(cell_size, capacity_pow2).write(&mut prologue);
+            data.write_all(&prologue)
+        };
+        match reserve() {
+            Ok(()) => Ok(path),
+            Err(err) => {
+                let _ = remove_file(&path);
+                Err(err)
+            }
+        }
+    }
+
+    /// mmap a file previously created by a `BucketStorage` and kept with `into_persisted`.
+    /// 'cell_size' and 'capacity_pow2' must match the values the file was created with.
+    /// 'config' must match the config the file was created with.
+    /// 'count' is incremented by the number of allocated cells found in the file.
+    /// Fails with `ChecksumMismatch` if the file was modified after it was last closed or dropped.
+    /// The file was not created by this storage, so it is kept when the storage is dropped
+    /// (`persist_on_drop`). Set `persist_on_drop` to false to remove it on drop instead.
+    pub fn open_existing(
+        path: PathBuf,
+        cell_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+        config: BucketStorageConfig,
+    ) -> Result<Self, BucketStorageError> {
+        BucketStorageBuilder::default()
+            .capacity_pow2(capacity_pow2)
+            .max_search(max_search)
+            .stats(stats)
+            .count(count)
+            .config(config)
+            .open(path, cell_size, OpenMode::ReadWrite)
+    }
+
+    /// same as `open_existing`, for a file that may not have been closed cleanly, by a writer that
+    /// marks every cell with `commit` once its contents are written. Every cell that is allocated
+    /// but not committed is freed, and returned along with the storage is the number of them.
+    /// Fails with `UidsUnsupported` for storages without cell headers, which cannot be committed.
+    /// A recorded contents checksum is not checked, since the writes it misses are being repaired.
+    pub fn open_existing_and_repair(
+        path: PathBuf,
+        cell_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+        config: BucketStorageConfig,
+    ) -> Result<(Self, u64), BucketStorageError> {
+        // checked before opening, which clears the contents checksum recorded in the file
+        if config.allocated_flag_location != IsAllocatedFlagLocation::InHeader {
+            return Err(BucketStorageError::UidsUnsupported);
+        }
+        let mut storage = BucketStorageBuilder::default()
+            .capacity_pow2(capacity_pow2)
+            .max_search(max_search)
+            .stats(stats)
+            .count(count)
+            .config(config)
+            .open(path, cell_size, OpenMode::Repair)?;
+        let repaired = storage.free_uncommitted();
+        Ok((storage, repaired))
+    }
+
+    /// same as `open_existing`, but the file is mapped without write access, so it cannot be modified.
+    /// Cells can be read, but allocating, freeing or writing cells fails or panics.
+    /// The file is left on disk when the storage is dropped.
+    pub fn open_readonly(
+        path: PathBuf,
+        cell_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+        config: BucketStorageConfig,
+    ) -> Result<Self, BucketStorageError> {
+        BucketStorageBuilder::default()
+            .capacity_pow2(capacity_pow2)
+            .max_search(max_search)
+            .stats(stats)
+            .count(count)
+            .config(config)
+            .open(path, cell_size, OpenMode::ReadOnly)
+    }
+
+    /// consume this storage, leaving its file on disk so it can be reopened later with `open_existing`.
+    /// Returns the path of the file.
+    /// Panics for an anonymous storage, which has no file.
+    pub fn into_persisted(mut self) -> PathBuf {
+        let path = self
+            .path
+            .clone()
+            .expect("an anonymous storage has no file to persist");
+        self.persist_on_drop = true;
+        path
+    }
+
+    /// a read-only view of this storage as of this call, so later writes to this storage are not
+    /// seen by the snapshot.
+    /// A private mapping of the same file would not do: Linux shows writes to the file in the pages
+    /// of a private mapping that have not been copied yet. Instead the snapshot maps a clone of the
+    /// file next to it, which shares the blocks of the file (a reflink) on filesystems that support
+    /// it, such as btrfs and xfs, so blocks are only copied when this storage writes them.
+    /// Other filesystems, such as ext4, copy the whole file while the snapshot is taken.
+    /// The clone is removed when the snapshot is dropped.
+    /// Writes made concurrently with this call may or may not be in the snapshot.
+    pub fn snapshot_cow(&self) -> io::Result<BucketStorage> {
+        self.snapshot_source()?.snapshot()
+    }
+
+    /// what `snapshot_cow` needs of this storage, so the snapshot can be taken on another thread
+    fn snapshot_source(&self) -> io::Result<SnapshotSource> {
+        if self.mmap.is_arena() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an arena region cannot be snapshotted",
+            ));
+        }
+        let Some(path) = &self.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an anonymous storage cannot be snapshotted",
+            ));
+        };
+        Ok(SnapshotSource {
+            path: path.clone(),
+            drives: Arc::clone(&self.drives),
+            cell_size: self.cell_size,
+            len: self.len(),
+            capacity_pow2: self.capacity_pow2,
+            stats: Arc::clone(&self.stats),
+            max_search: self.max_search,
+            config: self.config.clone(),
+        })
+    }
+
+    /// take the mapping and file out of this storage without removing or flushing the file,
+    /// so the caller manages their lifetime.
+    /// Rebuild a storage from them with `from_raw_parts`. The file no longer belongs to a storage,
+    /// so it is counted in `stats.bytes_freed`.
+    /// Storages that are not mapped with `MmapMut` (huge pages, read-only or arena storages)
+    /// and anonymous storages are returned unchanged as the error.
+    #[allow(clippy::result_large_err)]
+    pub fn into_raw_parts(self) -> Result<RawParts, Self> {
+        if !matches!(self.mmap, Mapping::Mmap(_)) || self.path.is_none() {
+            return Err(self);
+        }
+        if self.config.mlock_on_open {
+            // the caller owns the mapping from now on, `from_raw_parts` locks it again
+            munlock(&self.mmap);
+        }
+        // the caller owns the file from now on, `from_raw_parts` counts it again
+        self.count_bytes_freed();
+        let this = ManuallyDrop::new(self);
+        // every field that owns anything is moved out exactly once, and `this` is never dropped
+        let (mmap, path) = unsafe {
+            drop(std::ptr::read(&this.drives));
+            drop(std::ptr::read(&this.count));
+            drop(std::ptr::read(&this.stats));
+            drop(std::ptr::read(&this.resize_hook));
+            drop(std::ptr::read(&this.config));
+            drop(std::ptr::read(&this.borrowed_cells));
+            (std::ptr::read(&this.mmap), std::ptr::read(&this.path))
+        };
+        let (Mapping::Mmap(mmap), Some(path)) = (mmap, path) else {
+            unreachable!("checked above");
+        };
+        Ok(RawParts {
+            mmap,
+            path,
+            cell_size: this.cell_size,
+            capacity_pow2: this.capacity_pow2,
+        })
+    }
+
+    /// rebuild a storage from the parts returned by `into_raw_parts`.
+    /// 'config' must match the config the storage was created with.
+    /// 'count' is not changed. The mapping is locked again if 'config' sets `mlock_on_open`.
+    /// The file is counted in `stats.bytes_allocated`, as `into_raw_parts` counted it as freed.
+    pub fn from_raw_parts(
+        parts: RawParts,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+        config: BucketStorageConfig,
+    ) -> Result<Self, BucketStorageError> {
+        let RawParts {
+            mmap,
+            path,
+            cell_size,
+            capacity_pow2,
+        } = parts;
+        FilePrologue::check(&mmap, cell_size, capacity_pow2)?;
+        if config.mlock_on_open {
+            mlock_best_effort(&mmap, &stats);
+        }
+        let mut storage = Self {
+            drives: Self::drives_of(&path),
+            path: Some(path),
+            mmap: Mapping::Mmap(mmap),
+            cell_size,
+            count,
+            capacity_pow2,
+            stats,
+            max_search,
+            persist_on_drop: false,
+            bytes_counted: true,
+            resize_hook: None,
+            config,
+            writes_since_flush: AtomicU64::default(),
+            mutable_accesses: AtomicU64::default(),
+            closed: false,
+            borrowed_cells: Mutex::default(),
+            allocate_any_hint: 0,
+        };
+        if let Err(err) = storage.verify_layout() {
+            // the caller still owns the file
+            storage.closed = true;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err).into());
+        }
+        storage
+            .stats
+            .bytes_allocated
+            .fetch_add(storage.capacity_bytes(), Ordering::Relaxed);
+        Ok(storage)
+    }
+
+    /// count the bytes of this storage in `stats.bytes_freed`, if they were counted as allocated
+    fn count_bytes_freed(&self) {
+        if self.bytes_counted {
+            self.stats
+                .bytes_freed
+                .fetch_add(self.capacity_bytes(), Ordering::Relaxed);
+        }
+    }
+
+    /// release this storage, returning any error that `Drop` would have to ignore.
+    /// If the file is kept on disk (`persist_on_drop`), it is flushed first, otherwise it is removed.
+    pub fn close(mut self) -> io::Result<()> {
+        self.closed = true;
+        if self.persist_on_drop {
+            self.record_contents_checksum();
+            self.mmap.flush()
+        } else if self.mmap.is_arena() {
+            Ok(())
+        } else {
+            if let Some(path) = &self.path {
+                remove_file(path)?;
+            }
+            self.count_bytes_freed();
+            Ok(())
+        }
+    }
+
+    /// remove the file now instead of when this storage is dropped, to get its disk space back
+    /// while the storage is still held. The space of a removed file is only released once it
+    /// is no longer mapped, so the mapping is replaced by a zeroed anonymous one and cells read
+    /// as zero afterwards. Allocating then fails with `FileRemoved`, `try_get` returns None
+    /// and `Drop` leaves the file alone. Calling this again does nothing.
+    /// Fails with `Unsupported` for anonymous and arena storages, which have no file of their own.
+    pub fn remove_file_now(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if self.mmap.is_arena() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an arena region has no file of its own",
+            ));
+        }
+        let Some(path) = &self.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an anonymous storage has no file",
+            ));
+        };
+        let replacement = MmapMut::map_anon(self.mmap.len())?;
+        remove_file(path)?;
+        self.mmap = Mapping::Mmap(replacement);
+        self.closed = true;
+        self.count.store(0, self.config.count_ordering.store());
+        self.count_bytes_freed();
+        Ok(())
+    }
+
+    /// store the checksum of the file contents in the prologue of a file that is kept on disk,
+    /// for `open_existing` to check. Opening the file for writing clears it again, so only a file
+    /// modified while no writable storage had it open fails that check.
+    fn record_contents_checksum(&mut self) {
+        if !self.is_read_only() {
+            FilePrologue::write_contents_checksum(&mut self.mmap);
+        }
+    }
+
+    /// number of bytes mapped, including the prologue and any bitmap before the first cell
+    pub fn mmap_len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// catch a mapping shorter than the cells before any cell access panics on a bad slice
+    fn debug_assert_mapped(&self) {
+        debug_assert!(
+            self.mmap_len() as u64
+                >= Self::file_len_for(self.cell_size, self.capacity_pow2, &self.config),
+            "mapping of {} bytes is too short for {} cells of {} bytes",
+            self.mmap_len(),
+            self.capacity(),
+            self.cell_size
+        );
+    }
+
+    /// path of the file backing this storage, None for an anonymous storage
+    pub fn file_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// the drive the file backing this storage was created on, None for an anonymous storage
+    pub fn drive(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| {
+            path.parent()
+                .expect("bucket files are created inside a drive directory")
+        })
+    }
+
+    /// the drives resized copies of this storage are created on by `new_resized`.
+    /// Storages opened from an existing file use the drive the file is on.
+    pub fn drives(&self) -> &Arc<Vec<PathBuf>> {
+        &self.drives
+    }
+
+    /// the drive 'path' is on, as the drives of a storage opened from an existing file
+    fn drives_of(path: &Path) -> Arc<Vec<PathBuf>> {
+        Arc::new(path.parent().map(Path::to_path_buf).into_iter().collect())
+    }
+
+    /// the file path, or a placeholder for an anonymous storage, for log and error messages
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "<anonymous>".to_string(),
+        }
+    }
+
+    /// move this storage to a new file on one of 'drives', and remove the old file.
+    /// An anonymous storage is moved into a file, which is removed on drop like any other.
+    /// Later resizes with `new_resized` use 'drives' too.
+    /// The file layout does not depend on the drive, so the whole mapping is copied as is,
+    /// keeping every cell's index, header and contents.
+    pub fn relocate_to(&mut self, drives: &Arc<Vec<PathBuf>>) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "bucket storage is read-only",
+            ));
+        }
+        let (mmap, path) = self.copy_to_new_map(drives, &self.stats)?;
+        let old_mmap = std::mem::replace(&mut self.mmap, mmap);
+        let old_path = std::mem::replace(&mut self.path, Some(path));
+        self.drives = Arc::clone(drives);
+        if self.config.mlock_on_open {
+            munlock(&old_mmap);
+        }
+        if !old_mmap.is_arena() {
+            drop(old_mmap);
+            if let Some(old_path) = old_path {
+                remove_file(old_path)?;
+            }
+            self.count_bytes_freed();
+        }
+        // the new file was counted by `copy_to_new_map`
+        self.bytes_counted = true;
+        Ok(())
+    }
+
+    /// create an independent copy of this storage in a new file on the same drive.
+    /// The copy of an anonymous storage is anonymous too.
+    /// The copy has its own `count`, starting at `len`, and its own `stats`.
+    /// Its file is removed when it is dropped, unless it is persisted.
+    pub fn duplicate(&self) -> io::Result<Self> {
+        let stats = Arc::<BucketStats>::default();
+        let (mmap, path) = match self.drive() {
+            Some(drive) => {
+                let (mmap, path) = self.copy_to_new_map(&[drive.to_path_buf()], &stats)?;
+                (mmap, Some(path))
+            }
+            None => {
+                let mut mmap = MmapMut::map_anon(self.mmap.len())?;
+                mmap.copy_from_slice(&self.mmap);
+                stats
+                    .bytes_allocated
+                    .fetch_add(self.capacity_bytes(), Ordering::Relaxed);
+                (Mapping::Mmap(mmap), None)
+            }
+        };
+        Ok(Self {
+            path,
+            drives: Arc::clone(&self.drives),
+            mmap,
+            cell_size: self.cell_size,
+            count: Arc::new(AtomicU64::new(self.len())),
+            capacity_pow2: self.capacity_pow2,
+            stats,
+            max_search: self.max_search,
+            persist_on_drop: false,
+            bytes_counted: true,
+            resize_hook: None,
+            config: self.config.clone(),
+            writes_since_flush: AtomicU64::default(),
+            mutable_accesses: AtomicU64::default(),
+            closed: false,
+            borrowed_cells: Mutex::default(),
+            allocate_any_hint: 0,
+        })
+    }
+
+    /// create a new file on one of 'drives' and copy the whole mapping into it
+    fn copy_to_new_map(
+        &self,
+        drives: &[PathBuf],
+        stats: &BucketStats,
+    ) -> io::Result<(Mapping, PathBuf)> {
+        let (mut mmap, path) = Self::try_new_map(
+            drives,
+            self.cell_size as usize,
+            self.capacity_pow2,
+            stats,
+            &self.config,
+            &mut thread_rng(),
+        )?;
+        let len = mmap.len();
+        mmap.copy_from_slice(&self.mmap[..len]);
+        // the copy is writable, like a file opened for writing
+        FilePrologue::clear_contents_checksum(&mut mmap);
+        stats
+            .bytes_allocated
+            .fetch_add(self.capacity_bytes(), Ordering::Relaxed);
+        Ok((mmap, path))
+    }
+
+    /// number of bytes available to unprivileged users on the drive of this storage, see statvfs(3).
+    /// Used to decide whether a resize fits on this drive or the storage should move to another.
+    pub fn drive_free_bytes(&self) -> io::Result<u64> {
+        let drive = self.drive().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an anonymous storage is not on a drive",
+            )
+        })?;
+        free_bytes_on(drive)
+    }
+
+    /// non-zero if there is a header allocated prior to each element to store the 'allocated' bit
+    /// and, if enabled, the checksum of the cell
+    fn header_size_for(config: &BucketStorageConfig) -> usize {
+        let header_size = Self::flag_header_size_for(config);
+        if config.checksums {
+            header_size + CHECKSUM_SIZE
+        } else {
+            header_size
+        }
+    }
+
+    /// size of the part of the header holding the 'allocated' flag
+    fn flag_header_size_for(config: &BucketStorageConfig) -> usize {
+        match config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => {
+                Self::header_layout_size(HEADER_VERSION).expect("HEADER_VERSION is supported")
+            }
+            IsAllocatedFlagLocation::Bitmap => 0,
+        }
+    }
+
+    /// size of the `Header` of each cell in files with 'header_version', None if it is not supported
+    fn header_layout_size(header_version: u64) -> Option<usize> {
+        match header_version {
+            1 => Some(std::mem::size_of::<Header>()),
+            _ => None,
+        }
+    }
+
+    fn header_size(&self) -> usize {
+        Self::header_size_for(&self.config)
+    }
+
+    /// bytes of each cell available to the caller, after the header
+    pub fn cell_payload_size(&self) -> u64 {
+        self.cell_size - self.header_size() as u64
+    }
+
+    /// number of `T`s that fit in the payload of a cell, the longest `get_cell_slice::<T>`
+    pub fn elems_per_cell<T: Sized>(&self) -> u64 {
+        self.cell_payload_size() / std::mem::size_of::<T>() as u64
+    }
+
+    /// size of each cell, including its header, of a storage created with 'elem_size' and 'num_elems'
+    /// and the default config
+    pub fn cell_size_for(elem_size: u64, num_elems: u64) -> u64 {
+        Self::cell_size_for_config(elem_size, num_elems, &BucketStorageConfig::default())
+    }
+
+    /// same as `cell_size_for`, for a storage created with 'config'
+    pub fn cell_size_for_config(
+        elem_size: u64,
+        num_elems: u64,
+        config: &BucketStorageConfig,
+    ) -> u64 {
+        elem_size * num_elems + Self::header_size_for(config) as u64
+    }
+
+    pub fn max_search(&self) -> u64 {
+        self.max_search as u64
+    }
+
+    /// change how many cells are probed when searching for an entry. This only affects searches,
+    /// not the layout, so it can be raised as the storage fills and lowered after a shrink.
+    pub fn set_max_search(&mut self, max_search: MaxSearch) {
+        self.max_search = max_search;
+    }
+
+    pub fn new(
+        drives: Arc<Vec<PathBuf>>,
+        num_elems: u64,
+        elem_size: u64,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+    ) -> Self {
+        Self::new_with_capacity(
+            drives,
+            num_elems,
+            elem_size,
+            DEFAULT_CAPACITY_POW2,
+            max_search,
+            stats,
+            count,
+        )
+    }
+
+    /// fail before a file is allocated if 2^'capacity_pow2' cells of 'cell_size' are too large
+    fn check_capacity(
+        cell_size: u64,
+        capacity_pow2: u8,
+        config: &BucketStorageConfig,
+    ) -> Result<(), BucketStorageError> {
+        let too_large = capacity_pow2 > MAX_CAPACITY_POW2
+            || config.max_file_bytes.map_or(false, |max_file_bytes| {
+                (1u64 << capacity_pow2)
+                    .checked_mul(cell_size)
+                    .and_then(|bytes| {
+                        bytes.checked_add(Self::cells_offset_for(capacity_pow2, config) as u64)
+                    })
+                    .map_or(true, |bytes| bytes > max_file_bytes)
+            });
+        if too_large {
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2 })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// capacity_pow2 to resize this storage to when it is full: `grow_step_pow2` powers of two larger.
+    /// The result may still be rejected with `CapacityTooLarge` when the storage is created.
+    pub fn next_capacity_pow2(&self) -> u8 {
+        self.capacity_pow2
+            .saturating_add(self.config.grow_step_pow2.max(1))
+    }
+
+    /// bytes of the bitmap of 'allocated' flags, rounded up to whole u64s so cells stay aligned
+    fn bitmap_size_for(capacity_pow2: u8, config: &BucketStorageConfig) -> usize {
+        match config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => 0,
+            IsAllocatedFlagLocation::Bitmap => {
+                ((1usize << capacity_pow2) + u64::BITS as usize - 1) / u64::BITS as usize
+                    * std::mem::size_of::<u64>()
+            }
+        }
+    }
+
+    /// offset in the file of the first cell
+    fn cells_offset_for(capacity_pow2: u8, config: &BucketStorageConfig) -> usize {
+        PROLOGUE_SIZE + Self::bitmap_size_for(capacity_pow2, config)
+    }
+
+    /// size of the file of a storage with 2^'capacity_pow2' cells of 'cell_size'
+    fn file_len_for(cell_size: u64, capacity_pow2: u8, config: &BucketStorageConfig) -> u64 {
+        Self::cells_offset_for(capacity_pow2, config) as u64 + (1u64 << capacity_pow2) * cell_size
+    }
+
+    /// offset in the mmapped file of the start of cell 'ix', including its header
+    fn cell_offset(&self, ix: u64) -> usize {
+        Self::cells_offset_for(self.capacity_pow2, &self.config) + (ix * self.cell_size) as usize
+    }
+
+    /// return the bitmap word holding the 'allocated' flag of cell 'ix', and the flag's bit
+    fn bitmap_word(&self, ix: u64) -> (&AtomicU64, u64) {
+        assert_eq!(
+            self.config.allocated_flag_location,
+            IsAllocatedFlagLocation::Bitmap
+        );
+        assert!(ix < self.capacity(), "bad index size");
+        let offset = Self::bitmap_word_offset(ix);
+        let word_slice: &[u8] = &self.mmap[offset..offset + std::mem::size_of::<u64>()];
+        let word = unsafe { &*(word_slice.as_ptr() as *const AtomicU64) };
+        (word, Self::bitmap_bit(ix))
+    }
+
+    /// offset in the mapping of the bitmap word holding the 'allocated' flag of cell 'ix'
+    fn bitmap_word_offset(ix: u64) -> usize {
+        PROLOGUE_SIZE + (ix / u64::BITS as u64) as usize * std::mem::size_of::<u64>()
+    }
+
+    /// the bit of the 'allocated' flag of cell 'ix' in its bitmap word
+    fn bitmap_bit(ix: u64) -> u64 {
+        1 << (ix % u64::BITS as u64)
+    }
+
+    /// return ref to header of item 'ix' in mmapped file
+    /// the header only contains atomics, so a shared ref is enough to lock or unlock it
+    fn header_ptr(&self, ix: u64) -> &Header {
+        assert_eq!(
+            self.config.allocated_flag_location,
+            IsAllocatedFlagLocation::InHeader
+        );
+        let ix = self.cell_offset(ix);
+        let hdr_slice: &[u8] = &self.mmap[ix..ix + std::mem::size_of::<Header>()];
+        unsafe {
+            let hdr = hdr_slice.as_ptr() as *const Header;
+            hdr.as_ref().unwrap()
+        }
+    }
+
+    /// true if the entry at index 'ix' is free (as opposed to being allocated)
+    pub fn is_free(&self, ix: u64) -> bool {
+        // note that the terminology in the implementation is locked or unlocked.
+        // but our api is allocate/free
+        match self.config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => self.header_ptr(ix).is_unlocked(),
+            IsAllocatedFlagLocation::Bitmap => {
+                let (word, bit) = self.bitmap_word(ix);
+                word.load(Ordering::Acquire) & bit == 0
+            }
+        }
+    }
+
+    /// same as `is_free`, without checking that 'ix' is in bounds, for loops that already have.
+    ///
+    /// # Safety
+    ///
+    /// 'ix' must be less than `capacity`.
+    pub unsafe fn is_free_unchecked(&self, ix: u64) -> bool {
+        debug_assert!(ix < self.capacity(), "bad index size");
+        match self.config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => {
+                let header = self.mmap.as_ptr().add(self.cell_offset(ix)) as *const Header;
+                (*header).is_unlocked()
+            }
+            IsAllocatedFlagLocation::Bitmap => {
+                let word = self.mmap.as_ptr().add(Self::bitmap_word_offset(ix)) as *const AtomicU64;
+                (*word).load(Ordering::Acquire) & Self::bitmap_bit(ix) == 0
+            }
+        }
+    }
+
+    fn try_lock(&self, ix: u64) -> bool {
+        match self.config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => self.header_ptr(ix).try_lock(),
+            IsAllocatedFlagLocation::Bitmap => {
+                let (word, bit) = self.bitmap_word(ix);
+                word.fetch_or(bit, Ordering::Acquire) & bit == 0
+            }
+        }
+    }
+
+    /// mark cell 'ix' as free. It must be allocated, and not by `allocate_with_uid`.
+    fn unlock(&self, ix: u64) {
+        match self.config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => self.header_ptr(ix).unlock(),
+            IsAllocatedFlagLocation::Bitmap => {
+                let (word, bit) = self.bitmap_word(ix);
+                assert_ne!(word.fetch_and(!bit, Ordering::Release) & bit, 0);
+            }
+        }
+    }
+
+    /// 'uid's are stored in cell headers
+    fn check_uids_supported(&self) -> Result<(), BucketStorageError> {
+        match self.config.allocated_flag_location {
+            IsAllocatedFlagLocation::InHeader => Ok(()),
+            IsAllocatedFlagLocation::Bitmap => Err(BucketStorageError::UidsUnsupported),
+        }
+    }
+
+    /// true if this storage was opened with `open_readonly`
+    pub fn is_read_only(&self) -> bool {
+        self.mmap.is_read_only()
+    }
+
+    fn check_writable(&self) -> Result<(), BucketStorageError> {
+        if self.is_read_only() {
+            return Err(BucketStorageError::ReadOnly);
+        }
+        if self.closed {
+            return Err(BucketStorageError::FileRemoved);
+        }
+        Ok(())
+    }
+
+    fn assert_writable(&self) {
+        assert!(!self.is_read_only(), "bucket storage is read-only");
+        assert!(!self.closed, "bucket storage file was removed");
+    }
+
+    /// count a mutating operation, flushing if required by `FlushPolicy::EveryNWrites`
+    fn record_write(&self) {
+        self.record_writes(1);
+    }
+
+    /// count 'count' mutating operations at once, flushing at most once
+    fn record_writes(&self, count: u64) {
+        if let FlushPolicy::EveryNWrites(writes) = self.config.flush_policy {
+            if self.writes_since_flush.fetch_add(count, Ordering::Relaxed) + count >= writes {
+                self.writes_since_flush.store(0, Ordering::Relaxed);
+                self.stats.policy_flushes.fetch_add(1, Ordering::Relaxed);
+                if let Err(err) = self.mmap.flush_async() {
+                    log::error!("failed to flush bucket file {}: {err}", self.name());
+                }
+            }
+        }
+    }
+
+    /// if this access is sampled by `write_sample_rate`, time reading the start of 'item_slice',
+    /// which faults its page in if it is not resident.
+    /// The caller writes the cell, so only read it here to not race with other writers.
+    fn sample_write(&self, item_slice: &[u8]) {
+        let rate = self.config.write_sample_rate;
+        if rate == 0 || item_slice.is_empty() {
+            return;
+        }
+        if self.mutable_accesses.fetch_add(1, Ordering::Relaxed) % rate != 0 {
+            return;
+        }
+        let mut m = Measure::start("write_sample");
+        unsafe { std::ptr::read_volatile(item_slice.as_ptr()) };
+        m.stop();
+        self.stats
+            .write_sample_count
+            .fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .write_sample_us
+            .fetch_add(m.as_us(), Ordering::Relaxed);
+        self.stats
+            .write_sample_max_us
+            .fetch_max(m.as_us(), Ordering::Relaxed);
+    }
+
+    fn count_add(&self, cells: u64) {
+        self.count
+            .fetch_add(cells, self.config.count_ordering.update());
+    }
+
+    fn count_sub(&self, cells: u64) {
+        self.count
+            .fetch_sub(cells, self.config.count_ordering.update());
+    }
+
+    /// `Ok` if 'ix' is the index of a cell of this storage, otherwise `IndexOutOfBounds`.
+    /// Lets callers with indexes from untrusted or stale sources check them before calling
+    /// methods that panic on a bad index, such as `get` and `free`.
+    pub fn validate_index(&self, ix: u64) -> Result<(), BucketStorageError> {
+        if ix >= self.capacity() {
+            return Err(BucketStorageError::IndexOutOfBounds {
+                ix,
+                capacity: self.capacity(),
+            });
+        }
+        Ok(())
+    }
+
+    /// 'is_resizing' true if caller is resizing the index (so don't increment count)
+    /// 'is_resizing' false if caller is adding an item to the index (so increment count)
+    pub fn allocate(&mut self, ix: u64, is_resizing: bool) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        self.validate_index(ix)?;
+        let mut e = Err(BucketStorageError::AlreadyAllocated);
+        //debug!("ALLOC {} {}", ix, uid);
+        if self.try_lock(ix) {
+            e = Ok(());
+            self.update_checksum(ix);
+            if !is_resizing {
+                self.count_add(1);
+            }
+            self.record_write();
+        }
+        e
+    }
+
+    /// same as `allocate` when adding an item, but also reports whether occupancy is now above
+    /// `BucketStorageConfig::resize_threshold`. The storage cannot resize itself, since the new
+    /// file comes from the caller, so this only tells the caller when to.
+    pub fn allocate_or_hint(&mut self, ix: u64) -> Result<ResizeHint, BucketStorageError> {
+        self.allocate(ix, false)?;
+        Ok(if self.occupancy() > self.config.resize_threshold {
+            ResizeHint::NeedsResize
+        } else {
+            ResizeHint::None
+        })
+    }
+
+    /// allocate cell 'ix', recording 'uid' as its owner so only that owner can `free_with_uid` it.
+    /// 'uid' must not be `UID_UNLOCKED` and must not have the high bit set.
+    pub fn allocate_with_uid(&mut self, ix: u64, uid: u64) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        self.check_uids_supported()?;
+        self.validate_index(ix)?;
+        if uid == UID_UNLOCKED || uid & COMMITTED != 0 {
+            return Err(BucketStorageError::ReservedUid);
+        }
+        if !self.header_ptr(ix).try_lock_uid(uid) {
+            return Err(BucketStorageError::AlreadyAllocated);
+        }
+        self.update_checksum(ix);
+        self.count_add(1);
+        self.record_write();
+        Ok(())
+    }
+
+    /// free cell 'ix' if it was allocated by `allocate_with_uid` with 'uid'
+    pub fn free_with_uid(&mut self, ix: u64, uid: u64) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        self.check_uids_supported()?;
+        self.validate_index(ix)?;
+        if uid == UID_UNLOCKED {
+            return Err(BucketStorageError::ReservedUid);
+        }
+        self.header_ptr(ix)
+            .unlock_uid(uid)
+            .map_err(|owner| BucketStorageError::UidMismatch { uid, owner })?;
+        if self.config.scrub_on_free {
+            self.zero_payload(ix);
+        }
+        self.update_checksum(ix);
+        self.count_sub(1);
+        self.record_write();
+        Ok(())
+    }
+
+    /// the uid cell 'ix' was allocated with, or None if it is free
+    /// cells allocated by `allocate` report `UID_LOCKED`
+    pub fn uid(&self, ix: u64) -> Option<u64> {
+        if self.check_uids_supported().is_err() {
+            return (!self.is_free(ix)).then_some(UID_LOCKED);
+        }
+        let uid = self.header_ptr(ix).uid();
+        (uid != UID_UNLOCKED).then_some(uid)
+    }
+
+    /// mark allocated cell 'ix' as committed, once its contents are fully written.
+    /// A cell allocated but not committed when the process stopped may hold a partial write,
+    /// so `open_existing_and_repair` frees it. Freeing the cell clears the mark.
+    /// Fails with `UidsUnsupported` for storages without cell headers. Panics if the cell is free.
+    pub fn commit(&mut self, ix: u64) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        self.check_uids_supported()?;
+        self.validate_index(ix)?;
+        let header = self.header_ptr(ix);
+        assert!(!header.is_unlocked(), "cell {ix} is not allocated");
+        header.commit();
+        self.update_checksum(ix);
+        self.record_write();
+        Ok(())
+    }
+
+    /// true if cell 'ix' is allocated and was marked with `commit`
+    pub fn is_committed(&self, ix: u64) -> bool {
+        self.check_uids_supported().is_ok() && self.header_ptr(ix).is_committed()
+    }
+
+    /// free every allocated cell that was not committed, zeroing its contents.
+    /// Returns the number of cells freed.
+    fn free_uncommitted(&mut self) -> u64 {
+        let uncommitted = self
+            .allocated_indices()
+            .filter(|ix| !self.header_ptr(*ix).is_committed())
+            .collect::<Vec<_>>();
+        for ix in &uncommitted {
+            // the cell may be owned by any uid, so don't use `unlock`, which expects `UID_LOCKED`
+            self.header_ptr(*ix)
+                .lock
+                .store(UID_UNLOCKED, Ordering::Release);
+            self.zero_payload(*ix);
+            self.update_checksum(*ix);
+        }
+        self.count_sub(uncommitted.len() as u64);
+        uncommitted.len() as u64
+    }
+
+    /// the raw lock word in the header of cell 'ix', for tools that inspect a file.
+    /// Panics if the storage keeps allocated flags in a bitmap, and so has no headers.
+    pub fn raw_header(&self, ix: u64) -> u64 {
+        self.header_ptr(ix).lock.load(Ordering::Acquire)
+    }
+
+    /// overwrite the raw lock word in the header of cell 'ix', for repair tools and tests.
+    /// Panics if the storage is read-only or has no headers.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else is updated: storing a value that allocates or frees the cell leaves `count`
+    /// and the cell's checksum stale, and storing a uid other than `UID_LOCKED` in a storage
+    /// without uid support can make `free` panic. The caller must restore consistency, e.g. with
+    /// `reconcile_count`, before relying on them.
+    pub unsafe fn set_raw_header(&self, ix: u64, value: u64) {
+        self.assert_writable();
+        self.header_ptr(ix).lock.store(value, Ordering::Release);
+    }
+
+    /// allocate every cell in 'indices', adding to `count` once for the whole batch.
+    /// If any cell cannot be allocated, the cells already allocated by this call are freed again
+    /// and the error is returned.
+    pub fn allocate_batch(&mut self, indices: &[u64]) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        let capacity = self.capacity();
+        for (locked, ix) in indices.iter().enumerate() {
+            let result = if *ix >= capacity {
+                Err(BucketStorageError::IndexOutOfBounds { ix: *ix, capacity })
+            } else if !self.try_lock(*ix) {
+                Err(BucketStorageError::AlreadyAllocated)
+            } else {
+                Ok(())
+            };
+            if result.is_err() {
+                indices[..locked].iter().for_each(|ix| self.unlock(*ix));
+                return result;
+            }
+        }
+        indices.iter().for_each(|ix| self.update_checksum(*ix));
+        self.count_add(indices.len() as u64);
+        self.record_writes(indices.len() as u64);
+        Ok(())
+    }
+
+    /// return the first free cell in the 'max_search' cells starting at 'start', wrapping around
+    /// at the end of the storage. The cell is not allocated.
+    pub fn find_free(&self, start: u64) -> Option<u64> {
+        self.find_free_at_distance(start).map(|(_, ix)| ix)
+    }
+
+    /// `find_free`, also returning how many cells past 'start' the free cell is
+    fn find_free_at_distance(&self, start: u64) -> Option<(u64, u64)> {
+        let capacity = self.capacity();
+        (0..self.max_search())
+            .map(|distance| (distance, (start + distance) % capacity))
+            .find(|(_, ix)| self.is_free(*ix))
+    }
+
+    /// allocate the first free cell found by `find_free` and return its index
+    /// 'is_resizing' is passed through to `allocate`. Unless resizing, which only moves cells that
+    /// were already counted, the search distance of the allocated cell is recorded in `stats`.
+    pub fn allocate_from(
+        &mut self,
+        start: u64,
+        is_resizing: bool,
+    ) -> Result<u64, BucketStorageError> {
+        let (distance, ix) = self
+            .find_free_at_distance(start)
+            .ok_or(BucketStorageError::NoFreeCell)?;
+        self.allocate(ix, is_resizing)?;
+        if !is_resizing {
+            self.stats.record_search_distance(distance);
+        }
+        Ok(ix)
+    }
+
+    /// allocate any free cell and return its index, or None if every cell is allocated or the
+    /// storage cannot be written. For callers that do not care which cell they get.
+    /// The whole storage is scanned, starting just past the cell allocated by the previous call,
+    /// so consecutive calls do not rescan the cells they just filled.
+    pub fn try_allocate_any(&mut self) -> Option<u64> {
+        self.check_writable().ok()?;
+        let capacity = self.capacity();
+        let start = self.allocate_any_hint % capacity;
+        let ix = (0..capacity)
+            .map(|distance| (start + distance) % capacity)
+            .find(|ix| self.is_free(*ix) && self.try_lock(*ix))?;
+        self.update_checksum(ix);
+        self.count_add(1);
+        self.record_write();
+        self.allocate_any_hint = ix + 1;
+        Some(ix)
+    }
+
+    pub fn free(&mut self, ix: u64) {
+        self.assert_writable();
+        assert!(ix < self.capacity(), "bad index size");
+        self.unlock(ix);
+        if self.config.scrub_on_free {
+            self.zero_payload(ix);
+        }
+        self.update_checksum(ix);
+        self.count_sub(1);
+        self.record_write();
+    }
+
+    /// zero the contents of cell 'ix', leaving its header, and so its allocated state, unchanged
+    pub fn zero_cell(&mut self, ix: u64) {
+        self.zero_payload(ix);
+        self.update_checksum(ix);
+    }
+
+    fn zero_payload(&mut self, ix: u64) {
+        let start = self.get_start_offset(ix);
+        let end = self.cell_offset(ix) + self.cell_size as usize;
+        self.mmap[start..end].fill(0);
+    }
+
+    /// move allocated cells down into free cells until the allocated cells are a contiguous prefix.
+    /// Returns the highest allocated index afterwards, or None if no cells are allocated.
+    /// A moved cell keeps its contents, but not its index. This is only safe for storages whose
+    /// users do not depend on where a cell is, unlike an index bucket, where a key's position is
+    /// derived from its hash, or a data bucket, whose cells are referred to by index.
+    pub fn compact(&mut self) -> Option<u64> {
+        self.assert_writable();
+        let capacity = self.capacity();
+        let cell_size = self.cell_size as usize;
+        let mut low = 0;
+        let mut high = capacity;
+        loop {
+            while low < capacity && !self.is_free(low) {
+                low += 1;
+            }
+            while high > 0 && self.is_free(high - 1) {
+                high -= 1;
+            }
+            if high == 0 || low >= high - 1 {
+                break;
+            }
+            let src = high - 1;
+            let src_offset = self.cell_offset(src);
+            let dst_offset = self.cell_offset(low);
+            unsafe {
+                let src_ptr = self.mmap.as_ptr().add(src_offset);
+                let dst_ptr = self.mmap.as_mut_ptr().add(dst_offset);
+                std::ptr::copy_nonoverlapping(src_ptr, dst_ptr, cell_size);
+            }
+            match self.config.allocated_flag_location {
+                IsAllocatedFlagLocation::InHeader => {
+                    // the cell may be owned by any uid, so don't use `unlock`, which expects `UID_LOCKED`
+                    self.header_ptr(src)
+                        .lock
+                        .store(UID_UNLOCKED, Ordering::Release);
+                }
+                IsAllocatedFlagLocation::Bitmap => {
+                    self.try_lock(low);
+                    self.unlock(src);
+                }
+            }
+            self.update_checksum(src);
+        }
+        high.checked_sub(1)
+    }
+
+    /// copy the contents of every allocated cell of 'other' into a free cell of 'self', wherever
+    /// `try_allocate_any` finds one. Like `compact`, this does not keep indexes, so it is only
+    /// for storages whose users do not depend on where a cell is.
+    /// Fails with `NoFreeCell`, having allocated nothing, if 'self' does not have enough free
+    /// cells, or with `LayoutMismatch` if the storages lay out their cells differently.
+    pub fn merge_from(&mut self, other: &BucketStorage) -> Result<(), BucketStorageError> {
+        // `try_allocate_any` would report an unwritable storage as full
+        self.check_writable()?;
+        if self.cell_size != other.cell_size
+            || self.config.checksums != other.config.checksums
+            || self.config.allocated_flag_location != other.config.allocated_flag_location
+        {
+            return Err(BucketStorageError::LayoutMismatch);
+        }
+        let mut merged = Vec::with_capacity(other.count.load(Ordering::Relaxed) as usize);
+        for src in other.allocated_indices() {
+            let Some(dst) = self.try_allocate_any() else {
+                merged.into_iter().for_each(|dst| self.free(dst));
+                return Err(BucketStorageError::NoFreeCell);
+            };
+            self.get_bytes_mut(dst)
+                .copy_from_slice(other.get_bytes(src));
+            self.update_checksum(dst);
+            merged.push(dst);
+        }
+        Ok(())
+    }
+
+    /// free every cell without reallocating the file.
+    /// Fails with `ReadOnly` or `FileRemoved` if the storage cannot be written.
+    pub fn clear(&mut self) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        // all per-cell state, including the bitmap, lives in the mapping after the prologue,
+        // so zeroing it frees every cell
+        self.mmap[PROLOGUE_SIZE..].fill(0);
+        // a zeroed checksum does not match a zeroed cell
+        (0..self.capacity()).for_each(|ix| self.update_checksum(ix));
+        self.count.store(0, self.config.count_ordering.store());
+        self.allocate_any_hint = 0;
+        self.record_write();
+        Ok(())
+    }
+
+    /// write all dirty pages of the mapping to disk, blocking until they are durable
+    pub fn flush(&self) -> io::Result<()> {
+        let mut m = Measure::start("explicit_flush");
+        let result = self.mmap.flush();
+        m.stop();
+        self.stats
+            .explicit_flush_us
+            .fetch_add(m.as_us(), Ordering::Relaxed);
+        result
+    }
+
+    /// start writing all dirty pages of the mapping to disk without waiting for completion
+    pub fn flush_async(&self) -> io::Result<()> {
+        let mut m = Measure::start("explicit_flush_async");
+        let result = self.mmap.flush_async();
+        m.stop();
+        self.stats
+            .explicit_flush_us
+            .fetch_add(m.as_us(), Ordering::Relaxed);
+        result
+    }
+
+    /// ask the kernel to read in the pages of the 'count' cells starting at 'start', see `MmapAdvice::WillNeed`.
+    /// The range is clamped to the end of the storage.
+    pub fn prefetch(&self, start: u64, count: u64) -> io::Result<()> {
+        let end = start.saturating_add(count).min(self.capacity());
+        if start >= end {
+            return Ok(());
+        }
+        let range = self.cell_offset(start)..self.cell_offset(end);
+        MmapAdvice::WillNeed.advise(&self.mmap[range])
+    }
+
+    /// fault in every page of the mapping, so the first accesses after opening a file
+    /// do not wait for it to be read from disk.
+    /// Readahead of the whole mapping is requested first, then one byte of every page is read.
+    /// Mappings smaller than `WARMUP_MIN_BYTES` are cheap to fault in on demand, and are skipped.
+    /// The time taken is recorded in `warmup_us`.
+    pub fn warmup(&self) -> io::Result<()> {
+        let len = self.mmap.len();
+        if len < WARMUP_MIN_BYTES {
+            return Ok(());
+        }
+        let mut m = Measure::start("warmup");
+        MmapAdvice::WillNeed.advise(&self.mmap)?;
+        let start = self.mmap.as_ptr();
+        for offset in (0..len).step_by(WARMUP_STRIDE) {
+            unsafe { std::ptr::read_volatile(start.add(offset)) };
+        }
+        m.stop();
+        self.stats.warmup_us.fetch_add(m.as_us(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// true if any of the 'count' cells starting at 'start' is allocated.
+    /// The range is clamped to the end of the storage, like `release_range`.
+    pub fn any_allocated(&self, start: u64, count: u64) -> bool {
+        let end = start.saturating_add(count).min(self.capacity());
+        (start..end).any(|ix| !self.is_free(ix))
+    }
+
+    /// tell the kernel the pages of the 'count' free cells starting at 'start' are not needed
+    /// (`MADV_DONTNEED`), so they stop counting towards the resident set until touched again.
+    /// Only pages entirely inside the range are released, so neighboring cells are not affected.
+    /// The range is clamped to the end of the storage. Check it with `any_allocated` first,
+    /// since the contents of allocated cells in the range would be lost.
+    /// The file is shared, so the kernel keeps dirty contents and refaults them from the file,
+    /// but callers must treat the contents of released cells as lost.
+    #[cfg(unix)]
+    pub fn release_range(&mut self, start: u64, count: u64) -> io::Result<()> {
+        let end = start.saturating_add(count).min(self.capacity());
+        if start >= end {
+            return Ok(());
+        }
+        debug_assert!(
+            !self.any_allocated(start, count),
+            "only free cells can be released"
+        );
+        let page_size = page_size();
+        let base = self.mmap.as_ptr() as usize;
+        let range_start = base + self.cell_offset(start);
+        let range_end = base + self.cell_offset(end);
+        let aligned_start = (range_start + page_size - 1) / page_size * page_size;
+        let aligned_end = range_end / page_size * page_size;
+        if aligned_start >= aligned_end {
+            return Ok(());
+        }
+        let result = unsafe {
+            libc::madvise(
+                aligned_start as *mut libc::c_void,
+                aligned_end - aligned_start,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn release_range(&mut self, _start: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// calculate the crc32 of all of cell 'ix' except the bytes where the checksum itself is stored
+    fn calculate_checksum(&self, ix: u64) -> u32 {
+        let start = self.cell_offset(ix);
+        let checksum_start = start + Self::flag_header_size_for(&self.config);
+        let end = start + self.cell_size as usize;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.mmap[start..checksum_start]);
+        hasher.update(&self.mmap[checksum_start + CHECKSUM_SIZE..end]);
+        hasher.finalize()
+    }
+
+    fn checksum_offset(&self, ix: u64) -> usize {
+        self.cell_offset(ix) + Self::flag_header_size_for(&self.config)
+    }
+
+    /// store the checksum of cell 'ix', if checksums are enabled
+    fn update_checksum(&mut self, ix: u64) {
+        if self.config.checksums {
+            let checksum = self.calculate_checksum(ix) as u64;
+            let offset = self.checksum_offset(ix);
+            self.mmap[offset..offset + CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        }
+    }
+
+    /// write 'value' to cell 'ix' and update the cell's checksum
+    /// Writes through `get_mut` and friends do not update the checksum.
+    pub fn write_checked<T: Sized>(&mut self, ix: u64, value: T) {
+        assert!(self.config.checksums, "checksums are not enabled");
+        *self.get_mut(ix) = value;
+        self.update_checksum(ix);
+    }
+
+    /// true if the contents of cell 'ix' match its stored checksum.
+    /// Only meaningful for cells written by `allocate`, `free` or `write_checked`.
+    pub fn verify(&self, ix: u64) -> bool {
+        assert!(self.config.checksums, "checksums are not enabled");
+        assert!(ix < self.capacity(), "bad index size");
+        let offset = self.checksum_offset(ix);
+        let stored = u64::from_le_bytes(
+            self.mmap[offset..offset + CHECKSUM_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        stored == self.calculate_checksum(ix) as u64
+    }
+
+    pub fn get<T: Sized>(&self, ix: u64) -> &T {
+        let start = self.get_start_offset(ix);
+        let end = start + std::mem::size_of::<T>();
+        let item_slice: &[u8] = &self.mmap[start..end];
+        Self::debug_assert_aligned::<T>(item_slice);
+        unsafe {
+            let item = item_slice.as_ptr() as *const T;
+            &*item
+        }
+    }
+
+    /// same as `get`, without checking that 'ix' is in bounds, for loops that already have.
+    ///
+    /// # Safety
+    ///
+    /// 'ix' must be less than `capacity`, and the cell must be large enough for a `T`.
+    pub unsafe fn get_unchecked<T: Sized>(&self, ix: u64) -> &T {
+        debug_assert!(ix < self.capacity(), "bad index size");
+        let item = self
+            .mmap
+            .as_ptr()
+            .add(self.cell_offset(ix) + self.header_size());
+        debug_assert_eq!(
+            item as usize % std::mem::align_of::<T>(),
+            0,
+            "cell is not aligned for the requested type"
+        );
+        &*(item as *const T)
+    }
+
+    /// same as `get`, but returns None instead of panicking if 'ix' is out of bounds
+    pub fn try_get<T: Sized>(&self, ix: u64) -> Option<&T> {
+        (ix < self.capacity() && !self.closed).then(|| self.get(ix))
+    }
+
+    /// indexes of every cell, allocated or free
+    pub fn capacity_cells_range(&self) -> Range<u64> {
+        0..self.capacity()
+    }
+
+    /// iterate over the indexes of all allocated entries
+    pub fn allocated_indices(&self) -> impl Iterator<Item = u64> + '_ {
+        self.capacity_cells_range().filter(|ix| !self.is_free(*ix))
+    }
+
+    /// same as `allocated_indices`, but scans chunks of cells on the rayon thread pool.
+    /// Indexes are not produced in order.
+    pub fn par_allocated_indices(&self) -> impl ParallelIterator<Item = u64> + '_ {
+        let capacity = self.capacity();
+        let chunks = (capacity + PARALLEL_SCAN_CHUNK_CELLS - 1) / PARALLEL_SCAN_CHUNK_CELLS;
+        (0..chunks).into_par_iter().flat_map_iter(move |chunk| {
+            let start = chunk * PARALLEL_SCAN_CHUNK_CELLS;
+            let end = (start + PARALLEL_SCAN_CHUNK_CELLS).min(capacity);
+            (start..end).filter(|ix| !self.is_free(*ix))
+        })
+    }
+
+    /// iterate over all allocated entries, along with their index
+    pub fn iter_cells<T: Sized>(&self) -> impl Iterator<Item = (u64, &T)> + '_ {
+        self.allocated_indices().map(|ix| (ix, self.get(ix)))
+    }
+
+    /// same as `iter_cells`, but mutable, for rewriting every allocated cell in place.
+    /// Like `get_mut`, writes do not update cell checksums.
+    pub fn iter_cells_mut<T: Sized>(&mut self) -> impl Iterator<Item = (u64, &mut T)> + '_ {
+        let storage: &Self = self;
+        // every index is yielded once, so no two of the references are to the same cell,
+        // and borrowing 'self' mutably for the iterator keeps out any other reference
+        storage
+            .allocated_indices()
+            .map(move |ix| (ix, unsafe { &mut *storage.cell_mut_ptr(ix) }))
+    }
+
+    /// copy every allocated entry, along with its index, into an owned vec in index order
+    pub fn export_allocated<T: Copy>(&self) -> Vec<(u64, T)> {
+        self.iter_cells().map(|(ix, cell)| (ix, *cell)).collect()
+    }
+
+    pub fn get_empty_cell_slice<T: Sized + 'static>() -> &'static [T] {
+        &[]
+    }
+
+    /// true if the payload of every cell is aligned for `T`.
+    /// The mapping itself is page aligned, so only the offsets within the file matter.
+    pub fn is_aligned_for<T: Sized>(&self) -> bool {
+        let align = std::mem::align_of::<T>();
+        (self.cell_offset(0) + self.header_size()) % align == 0
+            && self.cell_size as usize % align == 0
+    }
+
+    /// casting a misaligned pointer to `&T` is undefined behavior
+    fn debug_assert_aligned<T: Sized>(item_slice: &[u8]) {
+        debug_assert_eq!(
+            item_slice.as_ptr() as usize % std::mem::align_of::<T>(),
+            0,
+            "cell is not aligned for the requested type"
+        );
+    }
+
+    fn get_start_offset(&self, ix: u64) -> usize {
+        assert!(ix < self.capacity(), "bad index size");
+        self.cell_offset(ix) + self.header_size()
+    }
+
+    pub fn get_cell_slice<T: Sized>(&self, ix: u64, len: u64) -> &[T] {
+        let start = self.get_start_offset(ix);
+        let end = start + std::mem::size_of::<T>() * len as usize;
+        //debug!("GET slice {} {}", start, end);
+        let item_slice: &[u8] = &self.mmap[start..end];
+        Self::debug_assert_aligned::<T>(item_slice);
+        unsafe {
+            let item = item_slice.as_ptr() as *const T;
+            std::slice::from_raw_parts(item, len as usize)
+        }
+    }
+
+    /// same as `get_cell_slice`, but returns None instead of reading past the end of the cell
+    /// or panicking if 'ix' or 'len' are out of bounds
+    pub fn try_get_cell_slice<T: Sized>(&self, ix: u64, len: u64) -> Option<&[T]> {
+        let fits = (std::mem::size_of::<T>() as u64)
+            .checked_mul(len)
+            .and_then(|bytes| bytes.checked_add(self.header_size() as u64))
+            .map(|bytes| bytes <= self.cell_size)
+            .unwrap_or_default();
+        (fits && ix < self.capacity()).then(|| self.get_cell_slice(ix, len))
+    }
+
+    /// the contents of cell 'ix' after its header, `cell_size` - `header_size` bytes,
+    /// for callers that only know the layout of the contents at runtime
+    pub fn get_bytes(&self, ix: u64) -> &[u8] {
+        &self.mmap[self.payload_range(ix)]
+    }
+
+    /// same as `get_bytes`, but mutable
+    pub fn get_bytes_mut(&mut self, ix: u64) -> &mut [u8] {
+        self.get_mut_cell_slice::<u8>(ix, self.payload_range(ix).len() as u64)
+    }
+
+    /// pointer to the first of the `payload_len` bytes of cell 'ix' after its header, to hand the
+    /// contents of a cell to foreign code without copying them.
+    /// The pointer is only valid while this storage is alive and not resized or remapped
+    /// (`grow_in_place`, `relocate_to`, `remove_file_now`), and reading through it is subject to
+    /// the usual aliasing rules for cells written through `get_mut` at the same time.
+    pub fn payload_ptr(&self, ix: u64) -> *const u8 {
+        self.mmap[self.payload_range(ix)].as_ptr()
+    }
+
+    /// same as `payload_ptr`, for foreign code that writes the contents of the cell.
+    /// Writing through it while any reference to the cell is alive is undefined behavior, and
+    /// like writes through `get_mut`, it does not update the cell's checksum.
+    /// Panics if the storage is read-only.
+    pub fn payload_ptr_mut(&self, ix: u64) -> *mut u8 {
+        self.assert_writable();
+        self.record_write();
+        self.payload_ptr(ix) as *mut u8
+    }
+
+    /// number of bytes at `payload_ptr`, the same for every cell
+    pub fn payload_len(&self) -> usize {
+        self.cell_payload_size() as usize
+    }
+
+    /// byte range of the mapping holding the contents of cell 'ix', ending at the end of the cell
+    fn payload_range(&self, ix: u64) -> Range<usize> {
+        let start = self.get_start_offset(ix);
+        let end = self.cell_offset(ix) + self.cell_size as usize;
+        start..end
+    }
+
+    pub fn get_mut<T: Sized>(&mut self, ix: u64) -> &mut T {
+        unsafe { &mut *self.cell_mut_ptr(ix) }
+    }
+
+    /// pointer to the `T` in cell 'ix', for `get_mut` and `lock_cell_mut` to hand out
+    fn cell_mut_ptr<T: Sized>(&self, ix: u64) -> *mut T {
+        self.assert_writable();
+        self.record_write();
+        let start = self.get_start_offset(ix);
+        let end = start + std::mem::size_of::<T>();
+        let item_slice: &[u8] = &self.mmap[start..end];
+        Self::debug_assert_aligned::<T>(item_slice);
+        self.sample_write(item_slice);
+        item_slice.as_ptr() as *mut T
+    }
+
+    pub fn get_mut_cell_slice<T: Sized>(&mut self, ix: u64, len: u64) -> &mut [T] {
+        self.assert_writable();
+        self.record_write();
+        let start = self.get_start_offset(ix);
+        let end = start + std::mem::size_of::<T>() * len as usize;
+        //debug!("GET mut slice {} {}", start, end);
+        let item_slice: &[u8] = &self.mmap[start..end];
+        Self::debug_assert_aligned::<T>(item_slice);
+        self.sample_write(item_slice);
+        unsafe {
+            let item = item_slice.as_ptr() as *mut T;
+            std::slice::from_raw_parts_mut(item, len as usize)
+        }
+    }
+
+    /// atomically replace the u64 at 'field_offset' bytes into the contents of cell 'ix' with 'new'
+    /// if it is 'expected', see `AtomicU64::compare_exchange`.
+    /// Returns the previous value, which is 'expected' on success.
+    /// The field must be 8 byte aligned and inside the cell.
+    pub fn cas_u64(
+        &self,
+        ix: u64,
+        field_offset: usize,
+        expected: u64,
+        new: u64,
+    ) -> Result<u64, u64> {
+        self.assert_writable();
+        let field_end = field_offset + std::mem::size_of::<u64>();
+        assert!(
+            (self.header_size() + field_end) as u64 <= self.cell_size,
+            "field at {field_offset} is outside the cell"
+        );
+        let start = self.get_start_offset(ix) + field_offset;
+        let field_slice: &[u8] = &self.mmap[start..start + std::mem::size_of::<u64>()];
+        assert_eq!(
+            field_slice.as_ptr() as usize % std::mem::align_of::<AtomicU64>(),
+            0,
+            "field at {field_offset} is not aligned"
+        );
+        let field = unsafe { &*(field_slice.as_ptr() as *const AtomicU64) };
+        let result = field.compare_exchange(expected, new, Ordering::AcqRel, Ordering::Acquire);
+        if result.is_ok() {
+            self.record_write();
+        }
+        result
+    }
+
+    /// same as `get_mut`, but through a shared reference, and returns None if 'ix' is out of bounds.
+    /// With debug assertions, also returns None if cell 'ix' is already held by another `CellGuard`,
+    /// to catch two mutable references to the same cell. Release builds do not track this.
+    ///
+    /// # Safety
+    ///
+    /// While the guard is alive, nothing else may access cell 'ix', on this thread or any other.
+    pub unsafe fn lock_cell_mut<T: Sized>(&self, ix: u64) -> Option<CellGuard<'_, T>> {
+        if ix >= self.capacity() {
+            return None;
+        }
+        if cfg!(debug_assertions) && !self.borrowed_cells.lock().unwrap().insert(ix) {
+            return None;
+        }
+        Some(CellGuard {
+            storage: self,
+            ix,
+            cell: &mut *self.cell_mut_ptr(ix),
+        })
+    }
+
+    /// create and map a new file on one of 'drives'.
+    /// 'rng' picks the drive, with `DriveSelection::Random`, and names the file. It is
+    /// `thread_rng` except in tests, which seed it to place files deterministically.
+    fn try_new_map<R: Rng + ?Sized>(
+        drives: &[PathBuf],
+        cell_size: usize,
+        capacity_pow2: u8,
+        stats: &BucketStats,
+        config: &BucketStorageConfig,
+        rng: &mut R,
+    ) -> io::Result<(Mapping, PathBuf)> {
+        // try every drive so that one failing drive doesn't fail the allocation
+        // keep each drive's index, for `BucketStats::per_drive`
+        let mut drives: Vec<(usize, &PathBuf)> = drives.iter().enumerate().collect();
+        match config.drive_selection {
+            DriveSelection::Random => drives.shuffle(rng),
+            DriveSelection::RoundRobin if !drives.is_empty() => {
+                let next = stats.next_drive.fetch_add(1, Ordering::Relaxed);
+                let first = (next % drives.len() as u64) as usize;
+                drives.rotate_left(first);
+            }
+            DriveSelection::RoundRobin => {}
+        }
+        let mut last_err = None;
+        for (drive_ix, drive) in drives {
+            match Self::try_new_map_on_drive(
+                drive,
+                drive_ix,
+                cell_size,
+                capacity_pow2,
+                stats,
+                config,
+                rng,
+            ) {
+                Ok(res) => return Ok(res),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no drives specified")))
+    }
+
+    /// create and map a new file on 'drive', which is at 'drive_ix' in the list of drives
+    fn try_new_map_on_drive<R: Rng + ?Sized>(
+        drive: &Path,
+        drive_ix: usize,
+        cell_size: usize,
+        capacity_pow2: u8,
+        stats: &BucketStats,
+        config: &BucketStorageConfig,
+        rng: &mut R,
+    ) -> io::Result<(Mapping, PathBuf)> {
+        let mut measure_new_file = Measure::start("measure_new_file");
+        let random = rng.gen_range(0, u128::MAX);
+        let pos = match &config.file_name_prefix {
+            Some(prefix) => format!("{prefix}.{random}"),
+            None => format!("{random}"),
+        };
+        let file = drive.join(pos);
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(file.clone())
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Unable to create data file {} in current dir({:?}): {:?}",
+                        file.display(),
+                        std::env::current_dir(),
+                        e
+                    ),
+                )
+            })?;
+
+        let mut map_file = || {
+            // Size the file up front so that we won't have to resize it later.
+            // set_len creates a sparse file, so no pages are written until cells are used.
+            // Sizing does no data IO, so there is nothing for O_DIRECT to keep out of the page cache.
+            //debug!("GROWING file {}", capacity * cell_size as u64);
+            let len = Self::file_len_for(cell_size as u64, capacity_pow2, config);
+            data.set_len(len)?;
+            measure_new_file.stop();
+            let mut measure_flush = Measure::start("measure_flush");
+            if config.sync_on_create {
+                data.sync_all()?;
+            }
+            measure_flush.stop();
+            let mut measure_mmap = Measure::start("measure_mmap");
+            let mut mmap = retry_on_enomem(stats, || Mapping::map(&data, len as usize, config))?;
+            FilePrologue::new(cell_size as u64, capacity_pow2).write(&mut mmap[..PROLOGUE_SIZE]);
+            if config.mmap_advice != MmapAdvice::Normal {
+                config.mmap_advice.advise(&mmap)?;
+            }
+            if config.mlock_on_open {
+                mlock_best_effort(&mmap, stats);
+            }
+            measure_mmap.stop();
+            stats.record_new_file(
+                drive_ix,
+                measure_new_file.as_us(),
+                measure_flush.as_us(),
+                measure_mmap.as_us(),
+            );
+            Ok(mmap)
+        };
+        match map_file() {
+            Ok(mmap) => Ok((mmap, file)),
+            Err(err) => {
+                // don't leave a partially created file behind
+                let _ = remove_file(&file);
+                Err(err)
+            }
+        }
+    }
+
+    /// copy contents from 'old_bucket' to 'self'
+    /// This is used by data buckets
+    /// Only allocated cells are written. 'self' must be a newly created file, whose cells read as
+    /// zero (free) without their pages ever being touched, so the rest of the file stays sparse.
+    fn copy_contents(&mut self, old_bucket: &Self) {
+        let increment = self
+            .capacity_pow2
+            .checked_sub(old_bucket.capacity_pow2)
+            .unwrap_or_else(|| {
+                panic!(
+                    "copy_contents cannot shrink from capacity_pow2 {} to {}",
+                    old_bucket.capacity_pow2, self.capacity_pow2
+                )
+            });
+        self.copy_cells(old_bucket, 1 << increment);
+    }
+
+    /// `copy_contents`, moving cell 'ix' of 'old_bucket' to 'ix' * 'index_grow'
+    fn copy_cells(&mut self, old_bucket: &Self, index_grow: u64) {
+        let mut m = Measure::start("grow");
+        debug_assert!(
+            (0..self.capacity())
+                .step_by((self.capacity() as usize / 8).max(1))
+                .all(|ix| self.is_free(ix)),
+            "resize target must be zero filled"
+        );
+        let old_cap = old_bucket.capacity();
+        let old_map = &old_bucket.mmap;
+        let new_bucket = &*self;
+        // returns true if cell 'i' was allocated, and so copied
+        let copy_cell = |i: &u64| {
+            let i = *i;
+            let allocated = !old_bucket.is_free(i);
+            if allocated {
+                match new_bucket.config.allocated_flag_location {
+                    IsAllocatedFlagLocation::InHeader => {
+                        // nothing to do when bit is in header
+                    }
+                    IsAllocatedFlagLocation::Bitmap => {
+                        new_bucket.try_lock(i * index_grow);
+                    }
+                }
+                let old_ix = old_bucket.cell_offset(i);
+                let new_ix = new_bucket.cell_offset(i * index_grow);
+                let dst_slice: &[u8] =
+                    &new_bucket.mmap[new_ix..new_ix + old_bucket.cell_size as usize];
+                let src_slice: &[u8] = &old_map[old_ix..old_ix + old_bucket.cell_size as usize];
+
+                unsafe {
+                    let dst = dst_slice.as_ptr() as *mut u8;
+                    let src = src_slice.as_ptr() as *const u8;
+                    std::ptr::copy_nonoverlapping(src, dst, old_bucket.cell_size as usize);
+                };
+            }
+            allocated
+        };
+        let copied = if old_cap < PARALLEL_COPY_MIN_CELLS {
+            (0..old_cap).filter(copy_cell).count() as u64
+        } else {
+            // every source cell has its own destination cell, so chunks can be copied concurrently
+            let chunks = (old_cap + PARALLEL_COPY_CHUNK_CELLS - 1) / PARALLEL_COPY_CHUNK_CELLS;
+            (0..chunks)
+                .into_par_iter()
+                .map(|chunk| {
+                    let start = chunk * PARALLEL_COPY_CHUNK_CELLS;
+                    let end = (start + PARALLEL_COPY_CHUNK_CELLS).min(old_cap);
+                    (start..end).filter(copy_cell).count() as u64
+                })
+                .sum()
+        };
+        m.stop();
+        // resized so update total file size
+        self.stats.resizes.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .record_growth(old_bucket.capacity_pow2, self.capacity_pow2);
+        self.stats.resize_us.fetch_add(m.as_us(), Ordering::Relaxed);
+        self.stats
+            .resize_bytes_copied
+            .fetch_add(copied * old_bucket.cell_size, Ordering::Relaxed);
+        self.stats
+            .resize_bytes_scanned
+            .fetch_add(old_cap * old_bucket.cell_size, Ordering::Relaxed);
+    }
+
+    pub fn update_max_size(&self) {
+        self.stats.update_max_size(self.capacity());
+    }
+
+    /// allocate a new bucket, copying data from 'bucket'.
+    /// The new file is created on 'drives', or if None, on the `drives` of 'bucket'.
+    pub fn new_resized(
+        drives: Option<&Arc<Vec<PathBuf>>>,
+        max_search: MaxSearch,
+        bucket: Option<&Self>,
+        capacity_pow_2: u8,
+        num_elems: u64,
+        elem_size: u64,
+        stats: &Arc<BucketStats>,
+    ) -> Self {
+        let drives = drives
+            .or(bucket.map(|bucket| &bucket.drives))
+            .expect("drives are required to create a bucket without a source bucket");
+        let mut new_bucket = BucketStorageBuilder::default()
+            .drives(Arc::clone(drives))
+            .elem(num_elems, elem_size)
+            .capacity_pow2(capacity_pow_2)
+            .max_search(max_search)
+            .stats(Arc::clone(stats))
+            .count(
+                bucket
+                    .map(|bucket| Arc::clone(&bucket.count))
+                    .unwrap_or_default(),
+            )
+            .config(
+                bucket
+                    .map(|bucket| bucket.config.clone())
+                    .unwrap_or_default(),
+            )
+            .build()
+            .unwrap();
+        if let Some(bucket) = bucket {
+            new_bucket.adopt_contents(bucket);
+        }
+        new_bucket.update_max_size();
+        new_bucket
+    }
+
+    /// same as `new_resized` from this storage, on its drives, but returns an error instead of
+    /// panicking. If the drives do not have space (ENOSPC, on unix) for 2^'capacity_pow2' cells,
+    /// each smaller capacity is tried in turn, down to the capacity of this storage:
+    /// `copy_contents` cannot shrink, and the current capacity holds every allocated cell.
+    /// The capacity achieved is the `capacity_pow2` of the returned storage.
+    /// A sparse file only runs out of space when its pages are first written, so the new file is
+    /// preallocated (on linux) before anything is copied into it, and is not sparse.
+    /// An anonymous storage is resized into a new anonymous storage.
+    pub fn try_resized(&self, capacity_pow2: u8) -> Result<Self, BucketStorageError> {
+        self.try_resized_with(capacity_pow2, |capacity_pow2| {
+            let resized = self.new_empty_like(capacity_pow2)?;
+            resized.preallocate_file()?;
+            Ok(resized)
+        })
+    }
+
+    /// `preallocate` the whole file of this storage. Anonymous storages and arena regions have
+    /// no file of their own, and are left alone.
+    fn preallocate_file(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) if !self.mmap.is_arena() => {
+                let file = OpenOptions::new().write(true).open(path)?;
+                preallocate(&file, file.metadata()?.len())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// create an empty storage of 2^'capacity_pow2' cells like this one: on its drives, or
+    /// anonymous if this storage is, with the same cell size, config, stats and count
+    fn new_empty_like(&self, capacity_pow2: u8) -> Result<Self, BucketStorageError> {
+        let builder = BucketStorageBuilder::default()
+            .drives(Arc::clone(&self.drives))
+            .elem(1, self.cell_payload_size())
+            .capacity_pow2(capacity_pow2)
+            .max_search(self.max_search)
+            .stats(Arc::clone(&self.stats))
+            .count(Arc::clone(&self.count))
+            .config(self.config.clone());
+        match &self.path {
+            Some(_) => builder.build(),
+            None => builder.build_anonymous(),
+        }
+    }
+
+    /// resize every storage in 'buckets' to 2^'new_pow2' cells with `try_resized`, on the rayon
+    /// thread pool, so the copies of a family of shards overlap. Each storage is replaced by its
+    /// resized copy, which shares its `count`. If any resize fails, the first error is returned
+    /// and every storage is left as it was.
+    pub fn resize_in_bulk(buckets: &mut [Self], new_pow2: u8) -> Result<(), BucketStorageError> {
+        let resized = buckets
+            .par_iter()
+            .map(|bucket| bucket.try_resized(new_pow2))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (bucket, resized) in buckets.iter_mut().zip(resized) {
+            *bucket = resized;
+        }
+        Ok(())
+    }
+
+    /// `try_resized`, with 'new_storage' creating the empty storage of a given capacity_pow2
+    fn try_resized_with(
+        &self,
+        capacity_pow2: u8,
+        mut new_storage: impl FnMut(u8) -> Result<Self, BucketStorageError>,
+    ) -> Result<Self, BucketStorageError> {
+        assert!(
+            capacity_pow2 >= self.capacity_pow2,
+            "try_resized cannot shrink from {} to {}",
+            self.capacity_pow2,
+            capacity_pow2
+        );
+        let mut target = capacity_pow2;
+        let mut new_bucket = loop {
+            match new_storage(target) {
+                Err(BucketStorageError::Io(err))
+                    if is_out_of_space(&err) && target > self.capacity_pow2 =>
+                {
+                    log::warn!(
+                        "no space to resize bucket {} to capacity_pow2 {target}, trying {}: {err}",
+                        self.name(),
+                        target - 1
+                    );
+                    target -= 1;
+                }
+                result => break result?,
+            }
+        };
+        new_bucket.adopt_contents(self);
+        new_bucket.update_max_size();
+        Ok(new_bucket)
+    }
+
+    /// copy the cells of 'bucket' into this newly created storage, and carry over its `resize_hook`
+    fn adopt_contents(&mut self, bucket: &Self) {
+        self.copy_contents(bucket);
+        if let Some(resize_hook) = &bucket.resize_hook {
+            resize_hook(bucket.capacity_pow2, self.capacity_pow2);
+            self.resize_hook = Some(Arc::clone(resize_hook));
+        }
+    }
+
+    /// write the allocated cells to 'w' in a format independent of the file layout.
+    /// All values are little-endian u64s:
+    /// the payload size of each cell (`cell_size` without the header), capacity_pow2 and the number
+    /// of allocated cells, followed by each allocated cell's index and payload bytes.
+    /// Read it back with `load_from_reader`.
+    pub fn snapshot_to_writer<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let payload_size = self.cell_payload_size();
+        let allocated = self.allocated_indices().collect::<Vec<_>>();
+        for value in [
+            payload_size,
+            self.capacity_pow2 as u64,
+            allocated.len() as u64,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        for ix in allocated {
+            w.write_all(&ix.to_le_bytes())?;
+            w.write_all(self.get_cell_slice::<u8>(ix, payload_size))?;
+        }
+        Ok(())
+    }
+
+    /// create a storage on 'drives' with 'config', holding the cells written by
+    /// `snapshot_to_writer`.
+    /// The sizes in the stream are checked before anything is allocated: a capacity beyond
+    /// `MAX_CAPACITY_POW2`, or a file size that overflows, is `CapacityTooLarge`, and more cells
+    /// than the capacity is `BadHeader`.
+    /// 'count' is incremented by the number of cells loaded, and is left alone if loading fails.
+    pub fn load_from_reader<R: Read>(
+        drives: Arc<Vec<PathBuf>>,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+        config: BucketStorageConfig,
+        reader: &mut R,
+    ) -> Result<Self, BucketStorageError> {
+        let read_u64 = |reader: &mut R| -> io::Result<u64> {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let payload_size = read_u64(reader)?;
+        let capacity_pow2 = read_u64(reader)?;
+        let num_cells = read_u64(reader)?;
+        let capacity_pow2 = u8::try_from(capacity_pow2)
+            .ok()
+            .filter(|capacity_pow2| *capacity_pow2 <= MAX_CAPACITY_POW2)
+            .ok_or(BucketStorageError::CapacityTooLarge {
+                capacity_pow2: u8::MAX,
+            })?;
+        let file_len = payload_size
+            .checked_add(Self::header_size_for(&config) as u64)
+            .and_then(|cell_size| cell_size.checked_mul(1 << capacity_pow2))
+            .and_then(|cells| {
+                cells.checked_add(Self::cells_offset_for(capacity_pow2, &config) as u64)
+            })
+            .and_then(|file_len| usize::try_from(file_len).ok());
+        if file_len.is_none() {
+            return Err(BucketStorageError::CapacityTooLarge { capacity_pow2 });
+        }
+        if num_cells > 1 << capacity_pow2 {
+            return Err(BucketStorageError::BadHeader);
+        }
+        let mut storage = BucketStorageBuilder::default()
+            .drives(drives)
+            .elem(1, payload_size)
+            .capacity_pow2(capacity_pow2)
+            .max_search(max_search)
+            .stats(stats)
+            .config(config)
+            .build()?;
+        // the cells are counted on a count of the storage's own until they are all loaded
+        for _ in 0..num_cells {
+            let ix = read_u64(reader)?;
+            storage.allocate(ix, false)?;
+            reader.read_exact(storage.get_mut_cell_slice::<u8>(ix, payload_size))?;
+        }
+        storage.count = count;
+        storage.count_add(num_cells);
+        Ok(storage)
+    }
+
+    /// make sure there is capacity for 'additional' more allocated cells at `FIT_LOAD_FACTOR`
+    /// occupancy, resizing at most once. The resized storage is created on the drives of this
+    /// storage, or is anonymous if this storage is.
+    /// Returns true if the storage was resized, or `CapacityTooLarge` if the cells would not fit
+    /// in `MAX_CAPACITY_POW2`.
+    pub fn reserve(&mut self, additional: u64) -> Result<bool, BucketStorageError> {
+        let needed = self.len().saturating_add(additional);
+        let new_pow2 = min_capacity_pow2(needed, FIT_LOAD_FACTOR);
+        if new_pow2 > MAX_CAPACITY_POW2 as u32 {
+            return Err(BucketStorageError::CapacityTooLarge {
+                capacity_pow2: new_pow2 as u8,
+            });
+        }
+        let new_pow2 = new_pow2 as u8;
+        if new_pow2 <= self.capacity_pow2 {
+            return Ok(false);
+        }
+        let mut resized = self.new_empty_like(new_pow2)?;
+        resized.adopt_contents(self);
+        resized.update_max_size();
+        // the old file is replaced, so only the new one should be kept
+        resized.persist_on_drop = std::mem::take(&mut self.persist_on_drop);
+        *self = resized;
+        Ok(true)
+    }
+
+    /// start resizing to 2^'new_pow2' cells, like `new_resized`, without blocking the caller.
+    /// A background thread takes a `snapshot_cow` of this storage and copies the cells from it,
+    /// so this storage can still be used while the resize is pending. Cells written while the
+    /// snapshot is taken may or may not be in it, `finish_resize` copies them again anyway.
+    /// Pass the result to `finish_resize` to get the resized storage.
+    pub fn begin_resize(&self, new_pow2: u8) -> io::Result<PendingResize> {
+        self.begin_resize_with(new_pow2, || {})
+    }
+
+    /// `begin_resize`, with 'before_copy' called on the background thread before the snapshot
+    fn begin_resize_with(
+        &self,
+        new_pow2: u8,
+        before_copy: impl FnOnce() + Send + 'static,
+    ) -> io::Result<PendingResize> {
+        assert!(
+            new_pow2 >= self.capacity_pow2,
+            "begin_resize cannot shrink from {} to {}",
+            self.capacity_pow2,
+            new_pow2
+        );
+        let source = self.snapshot_source()?;
+        let drives = Arc::clone(&self.drives);
+        let elem_size = self.cell_payload_size();
+        let max_search = self.max_search;
+        let stats = Arc::clone(&self.stats);
+        let config = self.config.clone();
+        let handle = std::thread::Builder::new()
+            .name("solBktResize".to_string())
+            .spawn(move || {
+                before_copy();
+                let snapshot = source.snapshot()?;
+                let mut resized = BucketStorageBuilder::default()
+                    .drives(drives)
+                    .elem(1, elem_size)
+                    .capacity_pow2(new_pow2)
+                    .max_search(max_search)
+                    .stats(stats)
+                    .config(config)
+                    .build()?;
+                resized.copy_contents(&snapshot);
+                Ok((snapshot, resized))
+            })?;
+        Ok(PendingResize {
+            new_capacity_pow2: new_pow2,
+            handle,
+        })
+    }
+
+    /// wait for 'pending' from `begin_resize` of this storage, and return the resized storage.
+    /// Cells that changed since the resize began are copied again, so nothing written to this
+    /// storage while the resize was pending is lost.
+    /// If the snapshot or the resized storage could not be created, the error is logged and this
+    /// storage is returned as is.
+    pub fn finish_resize(mut self, pending: PendingResize) -> Self {
+        let result = pending.handle.join().expect("background resize panicked");
+        let (snapshot, mut resized) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!(
+                    "failed to resize bucket {} to capacity_pow2 {}: {err:?}",
+                    self.name(),
+                    pending.new_capacity_pow2
+                );
+                return self;
+            }
+        };
+        resized.copy_changed_cells(&self, &snapshot);
+        resized.count = Arc::clone(&self.count);
+        if let Some(resize_hook) = &self.resize_hook {
+            resize_hook(self.capacity_pow2, resized.capacity_pow2);
+            resized.resize_hook = Some(Arc::clone(resize_hook));
+        }
+        // the old file is replaced, so only the new one should be kept
+        resized.persist_on_drop = std::mem::take(&mut self.persist_on_drop);
+        resized.update_max_size();
+        resized
+    }
+
+    /// copy every cell of 'current' that differs from 'snapshot', the state 'current' was in
+    /// when its cells were copied into this storage by `copy_contents`
+    fn copy_changed_cells(&mut self, current: &Self, snapshot: &Self) {
+        let increment = self.capacity_pow2 - current.capacity_pow2;
+        let cell_size = current.cell_size as usize;
+        let mut changed = 0;
+        for ix in current.capacity_cells_range() {
+            let offset = current.cell_offset(ix);
+            let cell = &current.mmap[offset..offset + cell_size];
+            let is_free = current.is_free(ix);
+            if is_free == snapshot.is_free(ix) && cell == &snapshot.mmap[offset..offset + cell_size]
+            {
+                continue;
+            }
+            let new_ix = ix << increment;
+            let new_offset = self.cell_offset(new_ix);
+            self.mmap[new_offset..new_offset + cell_size].copy_from_slice(cell);
+            if self.config.allocated_flag_location == IsAllocatedFlagLocation::Bitmap {
+                if is_free {
+                    if !self.is_free(new_ix) {
+                        self.unlock(new_ix);
+                    }
+                } else {
+                    self.try_lock(new_ix);
+                }
+            }
+            changed += 1;
+        }
+        log::debug!(
+            "{changed} cells of bucket {} changed during a background resize",
+            current.name()
+        );
+    }
+
+    /// grow this storage to 2^'new_pow2' cells, keeping every cell at its index.
+    /// The file is extended and its mapping grown with mremap where possible, or mapped again:
+    /// the mapping is shared with the file, so no cells are copied and the new cells are free.
+    /// A storage whose cells would move in its file, because they follow a bitmap that grows
+    /// with the capacity, or which has no file, is instead copied with `copy_contents`, at the same
+    /// indices, into a new storage like `try_resized` creates. Arena regions cannot grow.
+    /// Keeping indices is only correct for storages whose cells are addressed by absolute index.
+    /// An index bucket places keys by hash modulo capacity, so growing it requires re-hashing
+    /// every key into a storage from `new_resized`. Likewise, a data bucket resized by
+    /// `new_resized` has its cells moved to 'ix' * 2^increment, which this does not do.
+    pub fn grow_in_place(&mut self, new_pow2: u8) -> Result<(), BucketStorageError> {
+        self.check_writable()?;
+        assert!(
+            new_pow2 >= self.capacity_pow2,
+            "grow_in_place cannot shrink from {} to {}",
+            self.capacity_pow2,
+            new_pow2
+        );
+        Self::check_capacity(self.cell_size, new_pow2, &self.config)?;
+        if self.mmap.is_arena() {
+            return Err(BucketStorageError::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an arena region cannot grow in place",
+            )));
+        }
+        let old_pow2 = self.capacity_pow2;
+        let path = match &self.path {
+            Some(path)
+                if self.config.allocated_flag_location != IsAllocatedFlagLocation::Bitmap =>
+            {
+                path
+            }
+            _ => {
+                let mut grown = self.new_empty_like(new_pow2)?;
+                grown.copy_cells(self, 1);
+                grown.resize_hook = self.resize_hook.take();
+                grown.persist_on_drop = std::mem::take(&mut self.persist_on_drop);
+                // dropping the old storage munlocks its mapping and removes its file
+                *self = grown;
+                self.update_max_size();
+                if let Some(resize_hook) = &self.resize_hook {
+                    resize_hook(old_pow2, new_pow2);
+                }
+                return Ok(());
+            }
+        };
+        let mut m = Measure::start("grow_in_place");
+        let old_bytes = self.capacity_bytes();
+        let new_bytes = (1u64 << new_pow2) * self.cell_size;
+        let data = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = Self::file_len_for(self.cell_size, new_pow2, &self.config);
+        data.set_len(len)?;
+        if !self.mmap.try_remap(len as usize) {
+            let mmap = Mapping::map(&data, len as usize, &self.config)?;
+            if self.config.mlock_on_open {
+                mlock_best_effort(&mmap, &self.stats);
+                munlock(&self.mmap);
+            }
+            self.mmap = mmap;
+        }
+        FilePrologue::new(self.cell_size, new_pow2).write(&mut self.mmap[..PROLOGUE_SIZE]);
+        self.capacity_pow2 = new_pow2;
+        if self.config.mmap_advice != MmapAdvice::Normal {
+            self.config.mmap_advice.advise(&self.mmap)?;
+        }
+        m.stop();
+        self.stats.resizes.fetch_add(1, Ordering::Relaxed);
+        self.stats.record_growth(old_pow2, new_pow2);
+        self.stats.resize_us.fetch_add(m.as_us(), Ordering::Relaxed);
+        self.stats.resize_grow(old_bytes, new_bytes);
+        if self.bytes_counted {
+            self.stats
+                .bytes_allocated
+                .fetch_add(new_bytes - old_bytes, Ordering::Relaxed);
+        }
+        self.update_max_size();
+        if let Some(resize_hook) = &self.resize_hook {
+            resize_hook(old_pow2, new_pow2);
+        }
+        Ok(())
+    }
+
+    /// Return the number of bytes currently allocated
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity() * self.cell_size
+    }
+
+    /// same as `capacity_bytes`: the header of each cell is part of its cell size, so it is
+    /// already counted. The file prologue and any allocated bitmap are not.
+    pub fn total_bytes_including_header(&self) -> u64 {
+        self.capacity_bytes()
+    }
+
+    /// Return the number of cells currently allocated
+    pub fn capacity(&self) -> u64 {
+        1 << self.capacity_pow2
+    }
+
+    /// log2 of `capacity`. Only resizing changes it, so it always matches the mapping.
+    pub fn capacity_pow2(&self) -> u8 {
+        self.capacity_pow2
+    }
+
+    /// Return the number of cells that are in use, as counted by `count`
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// same as `len`, but loads `count` with `Ordering::Acquire`.
+    /// With `CountOrdering::AcqRel`, writes made before the counted allocations are visible.
+    pub fn len_acquire(&self) -> u64 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// true if no cells are in use
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// smallest capacity_pow2 that holds `len` cells at no more than `FIT_LOAD_FACTOR` occupancy,
+    /// between `DEFAULT_CAPACITY_POW2` and `MAX_CAPACITY_POW2`.
+    /// Compare with `capacity_pow2` to decide whether shrinking this storage is worthwhile.
+    pub fn fit_pow2(&self) -> u8 {
+        min_capacity_pow2(self.len(), FIT_LOAD_FACTOR)
+            .clamp(DEFAULT_CAPACITY_POW2 as u32, MAX_CAPACITY_POW2 as u32) as u8
+    }
+
+    /// Return the fraction of cells that are in use, from 0.0 to 1.0
+    pub fn occupancy(&self) -> f64 {
+        self.len() as f64 / self.capacity() as f64
+    }
+
+    /// true if every cell is in use
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    /// Return the number of cells that are not in use
+    pub fn remaining_capacity(&self) -> u64 {
+        self.capacity().saturating_sub(self.len())
+    }
+
+    /// check that the mapping is large enough for every cell, and that cells have room for their header.
+    /// Returns a description of the first problem found.
+    pub fn verify_layout(&self) -> Result<(), String> {
+        let header_size = self.header_size() as u64;
+        if self.cell_size < header_size {
+            return Err(format!(
+                "cell_size {} is smaller than the header size {}",
+                self.cell_size, header_size
+            ));
+        }
+        let expected_len = Self::file_len_for(self.cell_size, self.capacity_pow2, &self.config);
+        if (self.mmap.len() as u64) < expected_len {
+            return Err(format!(
+                "mapping of {} is {} bytes, but {} cells of {} bytes need {}",
+                self.name(),
+                self.mmap.len(),
+                self.capacity(),
+                self.cell_size,
+                expected_len
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &Arc<BucketStats> {
+        &self.stats
+    }
+
+    /// one line summary of the occupancy of this storage and its `stats`
+    pub fn stats_summary(&self) -> String {
+        format!(
+            "occupancy: {:.3}, count: {}, capacity: {}, {}",
+            self.occupancy(),
+            self.len(),
+            self.capacity(),
+            self.stats
+        )
+    }
+
+    /// log `stats_summary` at info level
+    pub fn log_stats(&self) {
+        log::info!("bucket {}: {}", self.name(), self.stats_summary());
+    }
+
+    /// Return the number of allocated cells, found by scanning every header instead of trusting `count`
+    pub fn audit_count(&self) -> u64 {
+        self.allocated_indices().count() as u64
+    }
+
+    /// set `count` to `audit_count`, repairing any drift from the headers.
+    /// `count` may be shared, so this is only correct if this storage is the only one counted by it.
+    pub fn reconcile_count(&self) {
+        self.count
+            .store(self.audit_count(), self.config.count_ordering.store());
+    }
+}
+
+/// A mutable reference to one cell, returned by `BucketStorage::lock_cell_mut`.
+/// The cell is released when the guard is dropped, including while unwinding from a panic.
+pub struct CellGuard<'a, T> {
+    storage: &'a BucketStorage,
+    ix: u64,
+    cell: &'a mut T,
+}
+
+impl<T> Deref for CellGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.cell
+    }
+}
+
+impl<T> DerefMut for CellGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.cell
+    }
+}
+
+impl<T> Drop for CellGuard<'_, T> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.storage.borrowed_cells.lock().unwrap().remove(&self.ix);
+        }
+    }
+}
+
+/// The file and layout of a storage to snapshot, see `BucketStorage::snapshot_cow`.
+struct SnapshotSource {
+    path: PathBuf,
+    drives: Arc<Vec<PathBuf>>,
+    cell_size: u64,
+    /// number of allocated cells when the source was taken
+    len: u64,
+    capacity_pow2: u8,
+    stats: Arc<BucketStats>,
+    max_search: MaxSearch,
+    config: BucketStorageConfig,
+}
+
+impl SnapshotSource {
+    /// clone the file next to it and map the clone read-only
+    fn snapshot(self) -> io::Result<BucketStorage> {
+        let random = thread_rng().gen_range(0, u128::MAX);
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".snapshot.{random}"));
+        let snapshot_path = self.path.with_file_name(name);
+        clone_file(&self.path, &snapshot_path)?;
+        let mmap = match File::open(&snapshot_path).and_then(|data| Mapping::map_read_only(&data)) {
+            Ok(mmap) => mmap,
+            Err(err) => {
+                let _ = remove_file(&snapshot_path);
+                return Err(err);
+            }
+        };
+        let snapshot = BucketStorage {
+            path: Some(snapshot_path),
+            drives: self.drives,
+            mmap,
+            cell_size: self.cell_size,
+            count: Arc::new(AtomicU64::new(self.len)),
+            capacity_pow2: self.capacity_pow2,
+            stats: self.stats,
+            max_search: self.max_search,
+            persist_on_drop: false,
+            bytes_counted: true,
+            resize_hook: None,
+            config: self.config,
+            writes_since_flush: AtomicU64::default(),
+            mutable_accesses: AtomicU64::default(),
+            closed: false,
+            borrowed_cells: Mutex::default(),
+            allocate_any_hint: 0,
+        };
+        // the clone is a file of its own, removed by the snapshot's drop
+        snapshot
+            .stats
+            .bytes_allocated
+            .fetch_add(snapshot.capacity_bytes(), Ordering::Relaxed);
+        Ok(snapshot)
+    }
+}
+
+/// A resize started by `BucketStorage::begin_resize`, to be completed by `finish_resize`.
+/// The result is the snapshot the cells were copied from and the resized storage.
+pub struct PendingResize {
+    new_capacity_pow2: u8,
+    handle: JoinHandle<Result<(BucketStorage, BucketStorage), BucketStorageError>>,
+}
+
+impl PendingResize {
+    /// true if the background copy is done, so `finish_resize` will not wait for it
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
+/// Names the many parameters of a new `BucketStorage`, so that the stats and count shared by a
+/// family of storages are passed on explicitly. `elem` must be set. Everything else defaults to
+/// what `BucketStorage::new` uses, with stats and a count of its own.
+/// Optional behavior is set with `config` rather than with more parameters.
+pub struct BucketStorageBuilder {
+    drives: Arc<Vec<PathBuf>>,
+    /// 'num_elems' and 'elem_size'
+    elem: Option<(u64, u64)>,
+    capacity_pow2: u8,
+    max_search: MaxSearch,
+    stats: Arc<BucketStats>,
+    count: Arc<AtomicU64>,
+    config: BucketStorageConfig,
+}
+
+impl Default for BucketStorageBuilder {
+    fn default() -> Self {
+        Self {
+            drives: Arc::default(),
+            elem: None,
+            capacity_pow2: DEFAULT_CAPACITY_POW2,
+            max_search: 1,
+            stats: Arc::default(),
+            count: Arc::default(),
+            config: BucketStorageConfig::default(),
+        }
+    }
+}
+
+impl BucketStorageBuilder {
+    pub fn drives(mut self, drives: Arc<Vec<PathBuf>>) -> Self {
+        self.drives = drives;
+        self
+    }
+
+    /// each cell holds 'num_elems' elements of 'elem_size' bytes
+    pub fn elem(mut self, num_elems: u64, elem_size: u64) -> Self {
+        self.elem = Some((num_elems, elem_size));
+        self
+    }
+
+    pub fn capacity_pow2(mut self, capacity_pow2: u8) -> Self {
+        self.capacity_pow2 = capacity_pow2;
+        self
+    }
+
+    /// enough capacity for 'expected_cells' allocated cells at no more than 'target_occupancy',
+    /// so that the storage does not need to be resized as it fills
+    pub fn capacity_for(mut self, expected_cells: u64, target_occupancy: f64) -> Self {
+        self.capacity_pow2 = capacity_pow2_for_elements(expected_cells, target_occupancy);
+        self
+    }
+
+    pub fn max_search(mut self, max_search: MaxSearch) -> Self {
+        self.max_search = max_search;
+        self
+    }
+
+    pub fn stats(mut self, stats: Arc<BucketStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn count(mut self, count: Arc<AtomicU64>) -> Self {
+        self.count = count;
+        self
+    }
+
+    pub fn config(mut self, config: BucketStorageConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// cell size of the storage, with the header required by the config
+    fn cell_size(&self) -> u64 {
+        let (num_elems, elem_size) = self.elem.expect("elem must be set");
+        BucketStorage::cell_size_for_config(elem_size, num_elems, &self.config)
+    }
+
+    /// create the storage in a new file on one of the drives
+    pub fn build(self) -> Result<BucketStorage, BucketStorageError> {
+        let cell_size = self.cell_size();
+        BucketStorage::check_capacity(cell_size, self.capacity_pow2, &self.config)?;
+        let (mmap, path) = BucketStorage::try_new_map(
+            &self.drives,
+            cell_size as usize,
+            self.capacity_pow2,
+            &self.stats,
+            &self.config,
+            &mut thread_rng(),
+        )?;
+        self.stats
+            .bytes_allocated
+            .fetch_add((1u64 << self.capacity_pow2) * cell_size, Ordering::Relaxed);
+        Ok(self.finish(mmap, Some(path), cell_size))
+    }
+
+    /// create the storage in anonymous memory instead of a file, for tests and indexes that are
+    /// never persisted. Nothing is left on disk, even if the storage is never dropped.
+    /// The drives are not used. The storage has no `file_path`, so it cannot be persisted,
+    /// snapshotted or grown in place. Resizing with `reserve` creates another anonymous storage.
+    pub fn build_anonymous(mut self) -> Result<BucketStorage, BucketStorageError> {
+        let cell_size = self.cell_size();
+        BucketStorage::check_capacity(cell_size, self.capacity_pow2, &self.config)?;
+        let len = BucketStorage::file_len_for(cell_size, self.capacity_pow2, &self.config);
+        // anonymous memory reads as zero, the same as a new sparse file
+        let mut mmap = Mapping::Mmap(MmapMut::map_anon(len as usize)?);
+        FilePrologue::new(cell_size, self.capacity_pow2).write(&mut mmap[..PROLOGUE_SIZE]);
+        if self.config.mmap_advice != MmapAdvice::Normal {
+            self.config.mmap_advice.advise(&mmap)?;
+        }
+        if self.config.mlock_on_open {
+            mlock_best_effort(&mmap, &self.stats);
+        }
+        self.stats
+            .bytes_allocated
+            .fetch_add((1u64 << self.capacity_pow2) * cell_size, Ordering::Relaxed);
+        self.drives = Arc::default();
+        Ok(self.finish(mmap, None, cell_size))
+    }
+
+    /// create the storage in a region of 'arena' instead of in a file of its own.
+    /// The drives are those of the arena. The region is returned to the arena when the storage
+    /// is dropped, so `persist_on_drop` only controls flushing, and the storage cannot
+    /// `grow_in_place`. Resizing with `new_resized` moves the cells to a file of their own.
+    pub fn build_in_arena(
+        mut self,
+        arena: &Arc<BucketArena>,
+    ) -> Result<BucketStorage, BucketStorageError> {
+        let cell_size = self.cell_size();
+        BucketStorage::check_capacity(cell_size, self.capacity_pow2, &self.config)?;
+        let len = BucketStorage::file_len_for(cell_size, self.capacity_pow2, &self.config);
+        let region = arena
+            .allocate(len as usize)
+            .ok_or(BucketStorageError::ArenaFull)?;
+        let mut mmap = Mapping::Arena(region);
+        FilePrologue::new(cell_size, self.capacity_pow2).write(&mut mmap[..PROLOGUE_SIZE]);
+        self.drives = BucketStorage::drives_of(arena.path());
+        Ok(self.finish(mmap, Some(arena.path().to_path_buf()), cell_size))
+    }
+
+    fn finish(self, mmap: Mapping, path: Option<PathBuf>, cell_size: u64) -> BucketStorage {
+        let storage = BucketStorage {
+            path,
+            drives: self.drives,
+            mmap,
+            cell_size,
+            count: self.count,
+            capacity_pow2: self.capacity_pow2,
+            stats: self.stats,
+            max_search: self.max_search,
+            persist_on_drop: false,
+            bytes_counted: true,
+            resize_hook: None,
+            config: self.config,
+            writes_since_flush: AtomicU64::default(),
+            mutable_accesses: AtomicU64::default(),
+            closed: false,
+            borrowed_cells: Mutex::default(),
+            allocate_any_hint: 0,
+        };
+        storage.debug_assert_mapped();
+        storage
+    }
+
+    /// map the file at 'path' with cells of 'cell_size', see `BucketStorage::open_existing`
+    fn open(
+        mut self,
+        path: PathBuf,
+        cell_size: u64,
+        mode: OpenMode,
+    ) -> Result<BucketStorage, BucketStorageError> {
+        let capacity_pow2 = self.capacity_pow2;
+        let read_only = mode == OpenMode::ReadOnly;
+        let data = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&path)?;
+        let expected_len = BucketStorage::file_len_for(cell_size, capacity_pow2, &self.config);
+        let file_len = data.metadata()?.len();
+        if file_len < PROLOGUE_SIZE as u64 {
+            return Err(BucketStorageError::BadHeader);
+        }
+        if file_len < expected_len {
+            return Err(BucketStorageError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "bucket file {} is {} bytes, expected at least {}",
+                    path.display(),
+                    file_len,
+                    expected_len
+                ),
+            )));
+        }
+        let mut measure_mmap = Measure::start("measure_mmap");
+        let mut mmap = if read_only {
+            Mapping::map_read_only(&data)?
+        } else {
+            Mapping::map(&data, file_len as usize, &self.config)?
+        };
+        if self.config.mlock_on_open {
+            mlock_best_effort(&mmap, &self.stats);
+        }
+        measure_mmap.stop();
+        self.stats
+            .mmap_us
+            .fetch_add(measure_mmap.as_us(), Ordering::Relaxed);
+        FilePrologue::check(&mmap, cell_size, capacity_pow2)?;
+        if let Some(checksum) = FilePrologue::read_contents_checksum(&mmap) {
+            if mode != OpenMode::Repair && checksum != FilePrologue::contents_checksum(&mmap) {
+                return Err(BucketStorageError::ChecksumMismatch);
+            }
+            if !read_only {
+                // the checksum stops matching with the first write, so a crash before the next
+                // clean close must not leave it behind to fail the next open
+                FilePrologue::clear_contents_checksum(&mut mmap);
+                mmap.flush_prologue()?;
+            }
+        }
+        self.drives = BucketStorage::drives_of(&path);
+        let mut storage = self.finish(mmap, Some(path), cell_size);
+        // the file was there before this storage, so it is kept for the next open, and was not
+        // counted as allocated
+        storage.persist_on_drop = true;
+        storage.bytes_counted = false;
+        let allocated = storage.allocated_indices().count();
+        storage.count_add(allocated as u64);
+        Ok(storage)
+    }
+}
+
+/// A `BucketStorage` shared by threads that write to it, behind a lock.
+/// Clones refer to the same storage.
+#[derive(Clone)]
+pub struct SyncBucketStorage(Arc<RwLock<BucketStorage>>);
+
+impl SyncBucketStorage {
+    pub fn new(storage: BucketStorage) -> Self {
+        Self(Arc::new(RwLock::new(storage)))
+    }
+
+    /// shared access, for reading cells. Readers do not block each other.
+    pub fn read(&self) -> RwLockReadGuard<'_, BucketStorage> {
+        self.0.read().unwrap()
+    }
+
+    /// exclusive access, for allocating, freeing and writing cells
+    pub fn write(&self) -> RwLockWriteGuard<'_, BucketStorage> {
+        self.0.write().unwrap()
+    }
+}
+
+/// A `BucketStorage` where every cell holds exactly one `T`.
+/// Cell accessors do not take a type parameter, so a cell cannot be read as the wrong type.
+/// Everything else (allocate, free, capacity, ...) is reached through `Deref`.
+pub struct TypedBucketStorage<T> {
+    storage: BucketStorage,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Sized> TypedBucketStorage<T> {
+    /// 'elem_size' must be `size_of::<T>()`
+    pub fn new_with_capacity(
+        drives: Arc<Vec<PathBuf>>,
+        elem_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+        count: Arc<AtomicU64>,
+    ) -> Self {
+        assert_eq!(
+            elem_size,
+            std::mem::size_of::<T>() as u64,
+            "elem_size does not match the size of the cell type"
+        );
+        let storage = BucketStorage::new_with_capacity(
+            drives,
+            1,
+            elem_size,
+            capacity_pow2,
+            max_search,
+            stats,
+            count,
+        );
+        assert!(
+            storage.is_aligned_for::<T>(),
+            "cell_size {} is not aligned for the cell type",
+            storage.cell_size
+        );
+        Self {
+            storage,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn get(&self, ix: u64) -> &T {
+        self.storage.get(ix)
+    }
+
+    pub fn get_mut(&mut self, ix: u64) -> &mut T {
+        self.storage.get_mut(ix)
+    }
+
+    /// iterate over all allocated entries, along with their index
+    pub fn iter_cells(&self) -> impl Iterator<Item = (u64, &T)> + '_ {
+        self.storage.iter_cells()
+    }
+
+    /// iterate over all allocated entries mutably, along with their index
+    pub fn iter_cells_mut(&mut self) -> impl Iterator<Item = (u64, &mut T)> + '_ {
+        self.storage.iter_cells_mut()
+    }
+
+    pub fn into_inner(self) -> BucketStorage {
+        self.storage
+    }
+}
+
+impl<T> Deref for TypedBucketStorage<T> {
+    type Target = BucketStorage;
+
+    fn deref(&self) -> &BucketStorage {
+        &self.storage
+    }
+}
+
+impl<T> DerefMut for TypedBucketStorage<T> {
+    fn deref_mut(&mut self) -> &mut BucketStorage {
+        &mut self.storage
+    }
+}
+
+/// A power of two number of `BucketStorage` shards addressed by a single u64 key.
+/// The high bits of a key select the shard and the remaining low bits are the index of the
+/// cell within that shard. Each shard has its own mapping and `count`, and is grown on its own.
+pub struct ShardedBucketStorage {
+    shards: Vec<BucketStorage>,
+    /// log2 of the number of shards
+    shard_bits: u32,
+}
+
+impl ShardedBucketStorage {
+    /// 'shards' must not be empty and its length must be a power of two
+    pub fn new(shards: Vec<BucketStorage>) -> Self {
+        assert!(
+            shards.len().is_power_of_two(),
+            "number of shards {} is not a power of two",
+            shards.len()
+        );
+        Self {
+            shard_bits: shards.len().trailing_zeros(),
+            shards,
+        }
+    }
+
+    /// create 'num_shards' storages of 2^'capacity_pow2' cells on 'drives', each counted separately
+    pub fn new_with_capacity(
+        drives: Arc<Vec<PathBuf>>,
+        num_shards: usize,
+        num_elems: u64,
+        elem_size: u64,
+        capacity_pow2: u8,
+        max_search: MaxSearch,
+        stats: Arc<BucketStats>,
+    ) -> Self {
+        Self::new(
+            (0..num_shards)
+                .map(|_| {
+                    BucketStorage::new_with_capacity(
+                        Arc::clone(&drives),
+                        num_elems,
+                        elem_size,
+                        capacity_pow2,
+                        max_search,
+                        Arc::clone(&stats),
+                        Arc::default(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// the shard 'key' is stored in
+    pub fn shard_of(&self, key: u64) -> usize {
+        key.checked_shr(u64::BITS - self.shard_bits).unwrap_or(0) as usize
+    }
+
+    /// the shard 'key' is stored in, and the index of its cell in that shard
+    pub fn locate(&self, key: u64) -> (usize, u64) {
+        (self.shard_of(key), key & (u64::MAX >> self.shard_bits))
+    }
+
+    /// the key of cell 'ix' of 'shard', the inverse of `locate`
+    pub fn key_of(&self, shard: usize, ix: u64) -> u64 {
+        debug_assert!(shard < self.num_shards());
+        debug_assert_eq!(ix & (u64::MAX >> self.shard_bits), ix);
+        (shard as u64)
+            .checked_shl(u64::BITS - self.shard_bits)
+            .unwrap_or(0)
+            | ix
+    }
+
+    pub fn shard(&self, shard: usize) -> &BucketStorage {
+        &self.shards[shard]
+    }
+
+    pub fn shard_mut(&mut self, shard: usize) -> &mut BucketStorage {
+        &mut self.shards[shard]
+    }
+
+    pub fn allocate(&mut self, key: u64) -> Result<(), BucketStorageError> {
+        let (shard, ix) = self.locate(key);
+        self.shards[shard].allocate(ix, false)
+    }
+
+    pub fn free(&mut self, key: u64) {
+        let (shard, ix) = self.locate(key);
+        self.shards[shard].free(ix)
+    }
+
+    pub fn is_free(&self, key: u64) -> bool {
+        let (shard, ix) = self.locate(key);
+        self.shards[shard].is_free(ix)
+    }
+
+    pub fn get<T: Sized>(&self, key: u64) -> &T {
+        let (shard, ix) = self.locate(key);
+        self.shards[shard].get(ix)
+    }
+
+    pub fn get_mut<T: Sized>(&mut self, key: u64) -> &mut T {
+        let (shard, ix) = self.locate(key);
+        self.shards[shard].get_mut(ix)
+    }
+
+    /// grow 'shard' to 2^'new_pow2' cells with `grow_in_place`, so every cell, and so every key,
+    /// of the shard stays where it is. The other shards are not touched.
+    pub fn grow_shard(&mut self, shard: usize, new_pow2: u8) -> Result<(), BucketStorageError> {
+        self.shards[shard].grow_in_place(new_pow2)
+    }
+
+    /// number of allocated cells in all shards
+    pub fn len(&self) -> u64 {
+        self.shards.iter().map(BucketStorage::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_shards(self) -> Vec<BucketStorage> {
+        self.shards
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bucket_stats::{DriveStats, GROWTH_HISTORY_LEN},
+        tempfile::tempdir,
+    };
+
+    #[test]
+    fn test_bucket_storage() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        assert!(!paths.is_empty());
+
+        let file_backed =
+            BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
+        let anonymous = BucketStorageBuilder::default()
+            .elem(1, 1)
+            .build_anonymous()
+            .unwrap();
+        assert!(anonymous.file_path().is_none());
+        // only the file-backed storage created a file
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+        for mut storage in [file_backed, anonymous] {
+            let ix = 0;
+            assert!(storage.is_free(ix));
+            assert!(storage.allocate(ix, false).is_ok());
+            assert!(storage.allocate(ix, false).is_err());
+            assert!(!storage.is_free(ix));
+            storage.free(ix);
+            assert!(storage.is_free(ix));
+            assert!(storage.is_free(ix));
+            assert!(storage.allocate(ix, false).is_ok());
+            assert!(storage.allocate(ix, false).is_err());
+            assert!(!storage.is_free(ix));
+            storage.free(ix);
+            assert!(storage.is_free(ix));
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_persist() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+
+        // default behavior removes the file on drop
+        let storage = BucketStorage::new(
+            Arc::clone(&drives),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let path = storage.file_path().unwrap().to_path_buf();
+        assert!(path.exists());
+        drop(storage);
+        assert!(!path.exists());
+
+        let mut storage = BucketStorage::new(
+            Arc::clone(&drives),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2;
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 42;
+        let path = storage.into_persisted();
+        assert!(path.exists());
+
+        let count = Arc::default();
+        let storage = BucketStorage::open_existing(
+            path.clone(),
+            cell_size,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(!storage.is_free(ix));
+        assert_eq!(*storage.get::<u64>(ix), 42);
+        (0..storage.capacity())
+            .filter(|i| *i != ix)
+            .for_each(|i| assert!(storage.is_free(i)));
+
+        // a file too short for the requested capacity is rejected
+        assert!(BucketStorage::open_existing(
+            path.clone(),
+            cell_size,
+            capacity_pow2 + 1,
+            1,
+            Arc::default(),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .is_err());
+
+        // the reopened storage keeps the file on drop, unless told otherwise
+        drop(storage);
+        assert!(path.exists());
+        let mut storage = BucketStorage::open_existing(
+            path.clone(),
+            cell_size,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(*storage.get::<u64>(ix), 42);
+        storage.persist_on_drop = false;
+        drop(storage);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bucket_storage_try_new_io_error() {
+        let tmpdir = tempdir().unwrap();
+        // a drive beneath a regular file can never be written to, even when running as root
+        let not_a_dir = tmpdir.path().join("not_a_dir");
+        std::fs::write(&not_a_dir, []).unwrap();
+        let paths: Vec<PathBuf> = vec![not_a_dir.join("drive")];
+
+        let result = BucketStorage::try_new_with_capacity(
+            Arc::new(paths),
+            1,
+            1,
+            DEFAULT_CAPACITY_POW2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(matches!(result, Err(BucketStorageError::Io(_))));
+    }
+
+    #[test]
+    fn test_bucket_storage_drive_fallback() {
+        let tmpdir = tempdir().unwrap();
+        let not_a_dir = tmpdir.path().join("not_a_dir");
+        std::fs::write(&not_a_dir, []).unwrap();
+        let bad_drive = not_a_dir.join("drive");
+        let good_drive = tmpdir.path().join("drive");
+        std::fs::create_dir(&good_drive).unwrap();
+        let drives = Arc::new(vec![bad_drive, good_drive.clone()]);
+
+        // the drive order is random, so try enough times to hit the bad drive first
+        for _ in 0..10 {
+            let storage = BucketStorage::try_new_with_capacity(
+                Arc::clone(&drives),
+                1,
+                1,
+                DEFAULT_CAPACITY_POW2,
+                1,
+                Arc::default(),
+                Arc::default(),
+            )
+            .unwrap();
+            assert_eq!(storage.drive(), Some(good_drive.as_path()));
+            assert!(storage.file_path().unwrap().exists());
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_allocated_indices() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(storage.allocated_indices().count(), 0);
+
+        let allocated = [1, 5, 6, 17, storage.capacity() - 1];
+        allocated.iter().for_each(|ix| {
+            storage.allocate(*ix, false).unwrap();
+            *storage.get_mut::<u64>(*ix) = *ix * 10;
+        });
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            allocated.to_vec()
+        );
+        assert_eq!(
+            storage
+                .iter_cells::<u64>()
+                .map(|(ix, value)| (ix, *value))
+                .collect::<Vec<_>>(),
+            allocated
+                .iter()
+                .map(|ix| (*ix, *ix * 10))
+                .collect::<Vec<_>>()
+        );
+
+        storage.free(5);
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            vec![1, 6, 17, storage.capacity() - 1]
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_checksums() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, elem_size)
+            .config(BucketStorageConfig {
+                checksums: true,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            storage.cell_size,
+            elem_size + (std::mem::size_of::<Header>() + CHECKSUM_SIZE) as u64
+        );
+
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        assert!(storage.verify(ix));
+        storage.write_checked(ix, 42u64);
+        assert!(storage.verify(ix));
+        assert_eq!(*storage.get::<u64>(ix), 42);
+
+        // corrupt the payload
+        let offset = storage.get_start_offset(ix);
+        storage.mmap[offset] ^= 1;
+        assert!(!storage.verify(ix));
+        storage.write_checked(ix, 42u64);
+        assert!(storage.verify(ix));
+
+        // corrupt the header
+        let offset = storage.cell_offset(ix);
+        storage.mmap[offset] ^= 2;
+        assert!(!storage.verify(ix));
+        storage.mmap[offset] ^= 2;
+        assert!(storage.verify(ix));
+
+        storage.free(ix);
+        assert!(storage.verify(ix));
+    }
+
+    #[test]
+    fn test_bucket_storage_sized_file() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let capacity_pow2 = 16;
+        for sync_on_create in [false, true] {
+            let stats = Arc::<BucketStats>::default();
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .capacity_pow2(capacity_pow2)
+                .stats(Arc::clone(&stats))
+                .config(BucketStorageConfig {
+                    sync_on_create,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            let len = PROLOGUE_SIZE as u64 + storage.capacity_bytes();
+            assert_eq!(
+                std::fs::metadata(storage.file_path().unwrap())
+                    .unwrap()
+                    .len(),
+                len
+            );
+            assert_eq!(storage.mmap.len() as u64, len);
+            // the whole mapping is usable, including the last cell
+            let last = storage.capacity() - 1;
+            assert!(storage.is_free(last));
+            *storage.get_mut::<u64>(last) = 1;
+            if !sync_on_create {
+                assert_eq!(stats.flush_file_us.load(Ordering::Relaxed), 0);
+            }
+            log::info!(
+                "sync_on_create: {}, new_file_us: {}, flush_file_us: {}",
+                sync_on_create,
+                stats.new_file_us.load(Ordering::Relaxed),
+                stats.flush_file_us.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_mmap_advice() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        for mmap_advice in [
+            MmapAdvice::Normal,
+            MmapAdvice::Random,
+            MmapAdvice::Sequential,
+            MmapAdvice::WillNeed,
+        ] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    mmap_advice,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            mmap_advice.advise(&storage.mmap).unwrap();
+            // sub ranges that don't start on a page boundary are allowed
+            mmap_advice.advise(&storage.mmap[1..2]).unwrap();
+            storage.allocate(0, false).unwrap();
+            assert!(!storage.is_free(0));
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_clear() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let count = Arc::default();
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+        );
+        [0, 2, 7, storage.capacity() - 1].iter().for_each(|ix| {
+            storage.allocate(*ix, false).unwrap();
+        });
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+
+        storage.clear().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert!((0..storage.capacity()).all(|ix| storage.is_free(ix)));
+
+        // the storage is usable after clearing
+        storage.allocate(2, false).unwrap();
+        assert!(!storage.is_free(2));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // cleared cells still verify against their checksums
+        let mut checked = BucketStorageBuilder::default()
+            .elem(1, std::mem::size_of::<u64>() as u64)
+            .config(BucketStorageConfig {
+                checksums: true,
+                ..BucketStorageConfig::default()
+            })
+            .build_anonymous()
+            .unwrap();
+        checked.allocate(3, false).unwrap();
+        checked.write_checked(3, 42u64);
+        checked.clear().unwrap();
+        assert!((0..checked.capacity()).all(|ix| checked.is_free(ix) && checked.verify(ix)));
+
+        // a read-only storage cannot be cleared
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2();
+        let path = storage.into_persisted();
+        let mut read_only = BucketStorage::open_readonly(
+            path,
+            cell_size,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            read_only.clear(),
+            Err(BucketStorageError::ReadOnly)
+        ));
+        assert!(!read_only.is_free(2));
+    }
+
+    #[test]
+    fn test_bucket_storage_concurrent_try_lock() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let successes = AtomicU64::default();
+        for ix in 0..storage.capacity() {
+            successes.store(0, Ordering::Relaxed);
+            std::thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        if storage.try_lock(ix) {
+                            successes.fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                }
+            });
+            assert_eq!(successes.load(Ordering::Relaxed), 1);
+            assert!(!storage.is_free(ix));
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_flush() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let ix = 0;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 42;
+        storage.flush().unwrap();
+        storage.flush_async().unwrap();
+        assert_eq!(*storage.get::<u64>(ix), 42);
+    }
+
+    #[test]
+    fn test_typed_bucket_storage() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = TypedBucketStorage::<u64>::new_with_capacity(
+            Arc::new(paths),
+            std::mem::size_of::<u64>() as u64,
+            DEFAULT_CAPACITY_POW2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut(ix) = 42;
+        assert_eq!(*storage.get(ix), 42);
+        assert_eq!(storage.iter_cells().collect::<Vec<_>>(), vec![(ix, &42)]);
+        storage.free(ix);
+        assert!(storage.is_free(ix));
+        assert_eq!(storage.capacity(), 1 << DEFAULT_CAPACITY_POW2);
+    }
+
+    #[test]
+    #[should_panic(expected = "elem_size does not match the size of the cell type")]
+    fn test_typed_bucket_storage_size_mismatch() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        TypedBucketStorage::<u64>::new_with_capacity(
+            Arc::new(paths),
+            std::mem::size_of::<u32>() as u64,
+            DEFAULT_CAPACITY_POW2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_prologue() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2;
+        assert_eq!(
+            FilePrologue::read(&storage.mmap[..PROLOGUE_SIZE]),
+            FilePrologue::new(cell_size, capacity_pow2)
+        );
+        let path = storage.into_persisted();
+
+        let open = |cell_size, capacity_pow2| {
+            BucketStorage::open_existing(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+        };
+        open(cell_size, capacity_pow2).unwrap();
+
+        // a layout other than the one the file was created with is rejected
+        assert!(matches!(
+            open(cell_size - 8, capacity_pow2),
+            Err(BucketStorageError::BadHeader)
+        ));
+        assert!(matches!(
+            open(cell_size, capacity_pow2 - 1),
+            Err(BucketStorageError::BadHeader)
+        ));
+
+        // a file with the wrong magic is rejected
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[0] ^= 0xff;
+        std::fs::write(&path, &contents).unwrap();
+        assert!(matches!(
+            open(cell_size, capacity_pow2),
+            Err(BucketStorageError::BadHeader)
+        ));
+
+        // so is a file too short to hold a prologue
+        std::fs::write(&path, &contents[..PROLOGUE_SIZE - 1]).unwrap();
+        assert!(matches!(
+            open(cell_size, capacity_pow2),
+            Err(BucketStorageError::BadHeader)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_header_version() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2;
+        assert_eq!(storage.header_size(), std::mem::size_of::<Header>());
+        let path = storage.into_persisted();
+
+        let header_version = HEADER_VERSION_OFFSET..HEADER_VERSION_OFFSET + 8;
+        let mut contents = std::fs::read(&path).unwrap();
+        assert_eq!(&contents[header_version.clone()], &1u64.to_le_bytes());
+        let open = || {
+            BucketStorage::open_existing(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+        };
+        open().unwrap();
+
+        // files written before the header version was stored are version 1
+        contents[header_version.clone()].fill(0);
+        std::fs::write(&path, &contents).unwrap();
+        open().unwrap();
+
+        contents[header_version].copy_from_slice(&2u64.to_le_bytes());
+        std::fs::write(&path, &contents).unwrap();
+        assert!(matches!(
+            open(),
+            Err(BucketStorageError::UnsupportedHeaderVersion { header_version: 2 })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bucket_storage_occupancy() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(storage.capacity(), 4);
+        assert_eq!(storage.occupancy(), 0.0);
+        assert_eq!(storage.remaining_capacity(), 4);
+        assert!(!storage.is_full());
+
+        storage.allocate(0, false).unwrap();
+        storage.allocate(1, false).unwrap();
+        assert_eq!(storage.occupancy(), 0.5);
+        assert_eq!(storage.remaining_capacity(), 2);
+        assert!(!storage.is_full());
+
+        storage.allocate(2, false).unwrap();
+        assert!(!storage.is_full());
+        storage.allocate(3, false).unwrap();
+        assert_eq!(storage.occupancy(), 1.0);
+        assert_eq!(storage.remaining_capacity(), 0);
+        assert!(storage.is_full());
+
+        storage.free(3);
+        assert!(!storage.is_full());
+        assert_eq!(storage.remaining_capacity(), 1);
+    }
+
+    #[test]
+    fn test_bucket_storage_copy_contents() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let large_pow2 = PARALLEL_COPY_MIN_CELLS.trailing_zeros() as u8;
+        // small buckets are copied serially, large ones in parallel
+        for capacity_pow2 in [2, large_pow2] {
+            let stats = Arc::<BucketStats>::default();
+            let mut old_bucket = BucketStorage::new_resized(
+                Some(&drives),
+                1,
+                None,
+                capacity_pow2,
+                1,
+                elem_size,
+                &stats,
+            );
+            let live = (0..old_bucket.capacity())
+                .filter(|ix| ix % 3 != 1)
+                .collect::<Vec<_>>();
+            live.iter().for_each(|ix| {
+                old_bucket.allocate(*ix, false).unwrap();
+                *old_bucket.get_mut::<u64>(*ix) = ix + 1000;
+            });
+
+            let increment = 2;
+            let new_bucket = BucketStorage::new_resized(
+                Some(&drives),
+                1,
+                Some(&old_bucket),
+                capacity_pow2 + increment,
+                1,
+                elem_size,
+                &stats,
+            );
+            let index_grow = 1 << increment;
+            let mut expected = live
+                .iter()
+                .map(|ix| (ix * index_grow, ix + 1000))
+                .collect::<Vec<_>>();
+            expected.sort_unstable();
+            let copied = new_bucket
+                .iter_cells::<u64>()
+                .map(|(ix, value)| (ix, *value))
+                .collect::<Vec<_>>();
+            assert_eq!(copied, expected);
+            assert_eq!(stats.resizes.load(Ordering::Relaxed), 1);
+            assert_eq!(
+                stats.resize_bytes_copied.load(Ordering::Relaxed),
+                live.len() as u64 * old_bucket.cell_size
+            );
+            assert_eq!(
+                stats.resize_bytes_scanned.load(Ordering::Relaxed),
+                old_bucket.capacity_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_out_of_bounds() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let capacity = storage.capacity();
+        assert!(matches!(
+            storage.allocate(capacity, false),
+            Err(BucketStorageError::IndexOutOfBounds { ix, capacity: cap }) if ix == capacity && cap == capacity
+        ));
+        assert_eq!(storage.count.load(Ordering::Relaxed), 0);
+
+        let last = capacity - 1;
+        storage.allocate(last, false).unwrap();
+        *storage.get_mut::<u64>(last) = 7;
+        assert_eq!(storage.try_get::<u64>(last), Some(&7));
+        assert_eq!(storage.try_get::<u64>(capacity), None);
+        assert_eq!(storage.try_get::<u64>(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_capacity_pow2_for_elements() {
+        // exact powers of two fit exactly at full occupancy
+        assert_eq!(capacity_pow2_for_elements(0, 1.0), 0);
+        assert_eq!(capacity_pow2_for_elements(1, 1.0), 0);
+        assert_eq!(capacity_pow2_for_elements(2, 1.0), 1);
+        assert_eq!(capacity_pow2_for_elements(32, 1.0), 5);
+        assert_eq!(capacity_pow2_for_elements(33, 1.0), 6);
+
+        // the target occupancy leaves room to spare
+        assert_eq!(capacity_pow2_for_elements(32, 0.5), 6);
+        assert_eq!(capacity_pow2_for_elements(33, 0.5), 7);
+        assert_eq!(capacity_pow2_for_elements(24, 0.75), 5);
+        assert_eq!(capacity_pow2_for_elements(25, 0.75), 6);
+
+        // huge requests are clamped
+        assert_eq!(
+            capacity_pow2_for_elements(1 << MAX_PRESIZED_CAPACITY_POW2, 1.0),
+            MAX_PRESIZED_CAPACITY_POW2
+        );
+        assert_eq!(
+            capacity_pow2_for_elements(1 << 30, 0.5),
+            MAX_PRESIZED_CAPACITY_POW2
+        );
+        assert_eq!(
+            capacity_pow2_for_elements(u64::MAX, 1.0),
+            MAX_PRESIZED_CAPACITY_POW2
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_new_presized() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, std::mem::size_of::<u64>() as u64)
+            .capacity_for(100, 0.5)
+            .build()
+            .unwrap();
+        assert_eq!(storage.capacity(), 256);
+    }
+
+    #[test]
+    fn test_bucket_storage_bytes_on_disk() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let stats = Arc::<BucketStats>::default();
+        let storages = (1..4)
+            .map(|capacity_pow2| {
+                BucketStorage::new_with_capacity(
+                    Arc::clone(&drives),
+                    1,
+                    std::mem::size_of::<u64>() as u64,
+                    capacity_pow2,
+                    1,
+                    Arc::clone(&stats),
+                    Arc::default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let total = storages.iter().map(|s| s.capacity_bytes()).sum::<u64>();
+        assert_eq!(stats.bytes_allocated.load(Ordering::Relaxed), total);
+        assert_eq!(stats.net_bytes_on_disk(), total);
+
+        drop(storages);
+        assert_eq!(stats.bytes_freed.load(Ordering::Relaxed), total);
+        assert_eq!(stats.net_bytes_on_disk(), 0);
+    }
+
+    #[test]
+    fn test_bucket_storage_bytes_on_disk_reopened() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let stats = Arc::<BucketStats>::default();
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::clone(&stats),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_bytes = storage.capacity_bytes();
+        let path = storage.into_persisted();
+        assert_eq!(stats.net_bytes_on_disk(), capacity_bytes);
+        let open = || {
+            BucketStorage::open_existing(
+                path.clone(),
+                cell_size,
+                DEFAULT_CAPACITY_POW2,
+                1,
+                Arc::clone(&stats),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+            .unwrap()
+        };
+
+        // a reopened file was not allocated again, so it is not freed again either
+        drop(open());
+        let mut storage = open();
+        storage.grow_in_place(DEFAULT_CAPACITY_POW2 + 1).unwrap();
+        assert_eq!(stats.net_bytes_on_disk(), capacity_bytes);
+        // but the file `relocate_to` creates is
+        storage
+            .relocate_to(&Arc::new(vec![tmpdir.path().to_path_buf()]))
+            .unwrap();
+        assert!(!path.exists());
+        let relocated_bytes = storage.capacity_bytes();
+        assert_eq!(stats.net_bytes_on_disk(), capacity_bytes + relocated_bytes);
+        storage.persist_on_drop = false;
+        drop(storage);
+        assert_eq!(stats.bytes_freed.load(Ordering::Relaxed), relocated_bytes);
+        // the persisted file is still counted, by the storage that created it
+        assert_eq!(stats.net_bytes_on_disk(), capacity_bytes);
+    }
+
+    #[test]
+    fn test_bucket_storage_huge_pages_fallback() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let cell_size = elem_size + std::mem::size_of::<Header>() as u64;
+        // large enough to ask for huge pages
+        let capacity_pow2 = (HUGE_PAGE_SIZE as u64 / cell_size).trailing_zeros() as u8;
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, elem_size)
+            .capacity_pow2(capacity_pow2)
+            .config(BucketStorageConfig {
+                use_huge_pages: true,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        assert!(storage.mmap.len() >= HUGE_PAGE_SIZE);
+        // the temp dir is not on hugetlbfs, so normal pages are used instead
+        #[cfg(target_os = "linux")]
+        {
+            assert!(matches!(storage.mmap, Mapping::Mmap(_)));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(storage.file_path().unwrap())
+                .unwrap();
+            assert!(HugePageMmap::map(&file, storage.mmap.len()).is_err());
+        }
+
+        let last = storage.capacity() - 1;
+        storage.allocate(last, false).unwrap();
+        *storage.get_mut::<u64>(last) = 42;
+        storage.flush().unwrap();
+        assert_eq!(*storage.get::<u64>(last), 42);
+    }
+
+    #[test]
+    fn test_bucket_storage_find_free() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let max_search = 3;
+        let stats = Arc::<BucketStats>::default();
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            3,
+            max_search,
+            Arc::clone(&stats),
+            Arc::default(),
+        );
+        let last = storage.capacity() - 1;
+        assert_eq!(storage.find_free(last), Some(last));
+
+        // the search wraps around to the start of the storage
+        storage.allocate(last, false).unwrap();
+        assert_eq!(storage.find_free(last), Some(0));
+        assert_eq!(storage.allocate_from(last, false).unwrap(), 0);
+        assert_eq!(storage.allocate_from(last, false).unwrap(), 1);
+        assert!(!storage.is_free(1));
+        assert_eq!(storage.count.load(Ordering::Relaxed), 3);
+
+        // every cell within max_search of 'last' is in use
+        assert_eq!(storage.find_free(last), None);
+        assert!(matches!(
+            storage.allocate_from(last, false),
+            Err(BucketStorageError::NoFreeCell)
+        ));
+        assert_eq!(storage.find_free(2), Some(2));
+
+        // only the cells allocated, not the searches, nor the failed allocation, are recorded
+        let histogram = stats.search_distance_histogram();
+        assert_eq!(histogram[1], 1);
+        assert_eq!(histogram[2], 1);
+        assert_eq!(histogram.iter().sum::<u64>(), 2);
+        storage.allocate_from(2, true).unwrap();
+        assert_eq!(stats.search_distance_histogram().iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_bucket_storage_grow_sparse() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let stats = Arc::<BucketStats>::default();
+        let mut old_bucket =
+            BucketStorage::new_resized(Some(&drives), 1, None, 2, 1, elem_size, &stats);
+        old_bucket.allocate(0, false).unwrap();
+        *old_bucket.get_mut::<u64>(0) = 5;
+
+        let new_bucket = BucketStorage::new_resized(
+            Some(&drives),
+            1,
+            Some(&old_bucket),
+            12,
+            1,
+            elem_size,
+            &stats,
+        );
+        assert_eq!(*new_bucket.get::<u64>(0), 5);
+        // cells that were never copied into read as free
+        assert!((1..new_bucket.capacity()).all(|ix| new_bucket.is_free(ix)));
+        assert_eq!(new_bucket.allocated_indices().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_bucket_storage_allocate_batch() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let count = Arc::default();
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+        );
+        storage.allocate_batch(&[0, 3, 5]).unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            vec![0, 3, 5]
+        );
+
+        // 5 is already allocated, so 1 and 2 are rolled back
+        assert!(matches!(
+            storage.allocate_batch(&[1, 2, 5, 6]),
+            Err(BucketStorageError::AlreadyAllocated)
+        ));
+        // a duplicate within the batch is rolled back too
+        assert!(matches!(
+            storage.allocate_batch(&[6, 6]),
+            Err(BucketStorageError::AlreadyAllocated)
+        ));
+        let capacity = storage.capacity();
+        assert!(matches!(
+            storage.allocate_batch(&[7, capacity]),
+            Err(BucketStorageError::IndexOutOfBounds { .. })
+        ));
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            vec![0, 3, 5]
+        );
+
+        storage.allocate_batch(&[1, 2, 6, 7]).unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 7);
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_cell_size_for() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        for (elem_size, num_elems) in [(1, 1), (8, 1), (8, 4), (48, 3)] {
+            let storage = BucketStorage::new(
+                Arc::clone(&drives),
+                num_elems,
+                elem_size,
+                1,
+                Arc::default(),
+                Arc::default(),
+            );
+            assert_eq!(
+                BucketStorage::cell_size_for(elem_size, num_elems),
+                storage.cell_size
+            );
+
+            let config = BucketStorageConfig {
+                checksums: true,
+                ..BucketStorageConfig::default()
+            };
+            let storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(num_elems, elem_size)
+                .config(config.clone())
+                .build()
+                .unwrap();
+            assert_eq!(
+                BucketStorage::cell_size_for_config(elem_size, num_elems, &config),
+                storage.cell_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_grow_in_place() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let stats = Arc::<BucketStats>::default();
+        let count = Arc::default();
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            1,
+            Arc::clone(&stats),
+            Arc::clone(&count),
+        );
+        for ix in 0..storage.capacity() {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix + 10;
+        }
+
+        storage.grow_in_place(4).unwrap();
+        assert_eq!(storage.capacity(), 16);
+        assert_eq!(
+            std::fs::metadata(storage.file_path().unwrap())
+                .unwrap()
+                .len(),
+            PROLOGUE_SIZE as u64 + storage.capacity_bytes()
+        );
+        assert_eq!(
+            storage.iter_cells::<u64>().collect::<Vec<_>>(),
+            vec![(0, &10), (1, &11)]
+        );
+        assert!((2..storage.capacity()).all(|ix| storage.is_free(ix)));
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.resizes.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.net_bytes_on_disk(), storage.capacity_bytes());
+
+        // the new cells are usable, and the file can be reopened at its new size
+        let last = storage.capacity() - 1;
+        storage.allocate(last, false).unwrap();
+        *storage.get_mut::<u64>(last) = 99;
+        let cell_size = storage.cell_size;
+        let path = storage.into_persisted();
+        let storage = BucketStorage::open_existing(
+            path,
+            cell_size,
+            4,
+            1,
+            Arc::default(),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(*storage.get::<u64>(last), 99);
+        assert_eq!(*storage.get::<u64>(1), 11);
+
+        // an anonymous storage has no file to extend, so its cells are copied at the same indices
+        for mlock_on_open in [false, true] {
+            let mut storage = BucketStorageBuilder::default()
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .capacity_pow2(1)
+                .config(BucketStorageConfig {
+                    mlock_on_open,
+                    ..BucketStorageConfig::default()
+                })
+                .build_anonymous()
+                .unwrap();
+            storage.allocate(1, false).unwrap();
+            *storage.get_mut::<u64>(1) = 11;
+            storage.grow_in_place(3).unwrap();
+            assert_eq!(storage.capacity(), 8);
+            assert_eq!(
+                storage.iter_cells::<u64>().collect::<Vec<_>>(),
+                vec![(1, &11)]
+            );
+            assert_eq!(storage.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_scrub_on_free() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        for scrub_on_free in [false, true] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(2, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    checksums: true,
+                    scrub_on_free,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            let ix = 1;
+            storage.allocate(ix, false).unwrap();
+            storage
+                .get_mut_cell_slice::<u64>(ix, 2)
+                .copy_from_slice(&[7, 8]);
+            storage.free(ix);
+            assert!(storage.is_free(ix));
+            assert!(storage.verify(ix));
+            let expected: &[u64] = if scrub_on_free { &[0, 0] } else { &[7, 8] };
+            assert_eq!(storage.get_cell_slice::<u64>(ix, 2), expected);
+        }
+
+        // zero_cell scrubs an allocated cell without freeing it
+        let mut storage = BucketStorage::new(
+            drives,
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let ix = 2;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 42;
+        storage.zero_cell(ix);
+        assert!(!storage.is_free(ix));
+        assert_eq!(*storage.get::<u64>(ix), 0);
+    }
+
+    #[test]
+    fn test_bucket_storage_file_name_prefix() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let new_storage = |file_name_prefix: Option<&str>| {
+            BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    file_name_prefix: file_name_prefix.map(str::to_string),
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap()
+        };
+        let file_name = |storage: &BucketStorage| {
+            storage
+                .file_path()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let storage = new_storage(None);
+        assert!(file_name(&storage).parse::<u128>().is_ok());
+
+        let storage = new_storage(Some("index_3"));
+        let other = new_storage(Some("index_3"));
+        let name = file_name(&storage);
+        let (prefix, random) = name.split_once('.').unwrap();
+        assert_eq!(prefix, "index_3");
+        assert!(random.parse::<u128>().is_ok());
+        // the random suffix keeps files with the same prefix apart
+        assert_ne!(storage.file_path(), other.file_path());
+    }
+
+    #[test]
+    fn test_bucket_storage_try_get_cell_slice() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let num_elems = 4;
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            num_elems,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let ix = 1;
+        storage
+            .get_mut_cell_slice::<u64>(ix, num_elems)
+            .copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(
+            storage.try_get_cell_slice::<u64>(ix, num_elems),
+            Some(&[1u64, 2, 3, 4][..])
+        );
+        assert_eq!(
+            storage.try_get_cell_slice::<u64>(ix, 2),
+            Some(&[1u64, 2][..])
+        );
+        assert_eq!(storage.try_get_cell_slice::<u64>(ix, 0), Some(&[][..]));
+
+        // too long for the cell
+        assert_eq!(storage.try_get_cell_slice::<u64>(ix, num_elems + 1), None);
+        assert_eq!(storage.try_get_cell_slice::<u64>(ix, u64::MAX), None);
+        // the last cell has no next cell to read into
+        assert_eq!(
+            storage.try_get_cell_slice::<u64>(storage.capacity() - 1, num_elems + 1),
+            None
+        );
+        assert_eq!(
+            storage.try_get_cell_slice::<u64>(storage.capacity(), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_uid() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let count = Arc::default();
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+        );
+        let (ix, uid, other_uid) = (2, 7, 8);
+        assert_eq!(storage.uid(ix), None);
+        assert!(matches!(
+            storage.allocate_with_uid(ix, UID_UNLOCKED),
+            Err(BucketStorageError::ReservedUid)
+        ));
+        storage.allocate_with_uid(ix, uid).unwrap();
+        assert!(!storage.is_free(ix));
+        assert_eq!(storage.uid(ix), Some(uid));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            storage.allocate_with_uid(ix, other_uid),
+            Err(BucketStorageError::AlreadyAllocated)
+        ));
+        assert!(storage.allocate(ix, false).is_err());
+
+        // only the owner can free the cell
+        assert!(matches!(
+            storage.free_with_uid(ix, other_uid),
+            Err(BucketStorageError::UidMismatch { uid: 8, owner: 7 })
+        ));
+        assert!(matches!(
+            storage.free_with_uid(ix, UID_UNLOCKED),
+            Err(BucketStorageError::ReservedUid)
+        ));
+        assert_eq!(storage.uid(ix), Some(uid));
+        storage.free_with_uid(ix, uid).unwrap();
+        assert!(storage.is_free(ix));
+        assert_eq!(storage.uid(ix), None);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert!(matches!(
+            storage.free_with_uid(ix, uid),
+            Err(BucketStorageError::UidMismatch {
+                uid: 7,
+                owner: UID_UNLOCKED
+            })
+        ));
+
+        // plain allocate keeps using UID_LOCKED
+        storage.allocate(ix, false).unwrap();
+        assert_eq!(storage.uid(ix), Some(UID_LOCKED));
+    }
+
+    #[test]
+    fn test_bucket_storage_open_readonly() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2;
+        for ix in [1, 4] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix * 10;
+        }
+        assert!(!storage.is_read_only());
+        let path = storage.into_persisted();
+
+        let count = Arc::default();
+        let mut storage = BucketStorage::open_readonly(
+            path.clone(),
+            cell_size,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert!(storage.is_read_only());
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.allocated_indices().collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(*storage.get::<u64>(4), 40);
+        assert_eq!(storage.get_cell_slice::<u64>(1, 1), &[10]);
+        assert!(storage.is_free(0));
+        assert_eq!(storage.find_free(0), Some(0));
+        storage.flush().unwrap();
+
+        assert!(matches!(
+            storage.allocate(0, false),
+            Err(BucketStorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.allocate_batch(&[0]),
+            Err(BucketStorageError::ReadOnly)
+        ));
+        assert!(storage.is_free(0));
+
+        // the file is not removed when a read-only storage is dropped
+        drop(storage);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket storage is read-only")]
+    fn test_bucket_storage_readonly_get_mut() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2;
+        let path = storage.into_persisted();
+        let mut storage = BucketStorage::open_readonly(
+            path,
+            cell_size,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        *storage.get_mut::<u64>(0) = 1;
+    }
+
+    #[test]
+    fn test_bucket_storage_prefetch() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let capacity = storage.capacity();
+        storage.prefetch(0, capacity).unwrap();
+        storage.prefetch(3, 4).unwrap();
+        storage.prefetch(3, 0).unwrap();
+        // ranges past the end are clamped
+        storage.prefetch(capacity - 2, 10).unwrap();
+        storage.prefetch(capacity - 2, u64::MAX).unwrap();
+        storage.prefetch(capacity, 1).unwrap();
+    }
+
+    #[test]
+    fn test_bucket_storage_resize_hook() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let stats = Arc::default();
+        let resizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut bucket =
+            BucketStorage::new_resized(Some(&drives), 1, None, 2, 1, elem_size, &stats);
+        let resizes_clone = Arc::clone(&resizes);
+        bucket.resize_hook = Some(Arc::new(move |old, new| {
+            resizes_clone.lock().unwrap().push((old, new))
+        }));
+
+        let bucket =
+            BucketStorage::new_resized(Some(&drives), 1, Some(&bucket), 3, 1, elem_size, &stats);
+        assert_eq!(*resizes.lock().unwrap(), vec![(2, 3)]);
+
+        // the hook is carried over to the resized storage
+        let mut bucket =
+            BucketStorage::new_resized(Some(&drives), 1, Some(&bucket), 5, 1, elem_size, &stats);
+        bucket.grow_in_place(6).unwrap();
+        assert_eq!(*resizes.lock().unwrap(), vec![(2, 3), (3, 5), (5, 6)]);
+
+        // no hook, no calls
+        bucket.resize_hook = None;
+        bucket.grow_in_place(7).unwrap();
+        assert_eq!(resizes.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_bucket_storage_file_path() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(storage.file_path().unwrap().is_file());
+        assert!(storage.file_path().unwrap().starts_with(tmpdir.path()));
+        assert_eq!(storage.drive(), Some(tmpdir.path()));
+        let path = storage.file_path().unwrap().to_path_buf();
+        drop(storage);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bucket_storage_resize_same_capacity() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let stats = Arc::default();
+        let mut bucket =
+            BucketStorage::new_resized(Some(&drives), 1, None, 3, 1, elem_size, &stats);
+        bucket.allocate(5, false).unwrap();
+        *bucket.get_mut::<u64>(5) = 9;
+        // copying to a storage of the same capacity keeps every cell at its index
+        let bucket =
+            BucketStorage::new_resized(Some(&drives), 1, Some(&bucket), 3, 1, elem_size, &stats);
+        assert_eq!(
+            bucket.iter_cells::<u64>().collect::<Vec<_>>(),
+            vec![(5, &9)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "copy_contents cannot shrink from capacity_pow2 3 to 2")]
+    fn test_bucket_storage_resize_smaller_capacity() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let stats = Arc::default();
+        let bucket = BucketStorage::new_resized(Some(&drives), 1, None, 3, 1, elem_size, &stats);
+        BucketStorage::new_resized(Some(&drives), 1, Some(&bucket), 2, 1, elem_size, &stats);
+    }
+
+    #[test]
+    fn test_bucket_storage_snapshot_round_trip() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let num_elems = 3;
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::clone(&drives))
+            .elem(num_elems, std::mem::size_of::<u64>() as u64)
+            .capacity_pow2(4)
+            .config(BucketStorageConfig {
+                // the snapshot does not depend on the header layout
+                checksums: true,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        for ix in [0, 7, 15] {
+            storage.allocate(ix, false).unwrap();
+            storage
+                .get_mut_cell_slice::<u64>(ix, num_elems)
+                .copy_from_slice(&[ix, ix + 1, ix + 2]);
+        }
+        let mut snapshot = Vec::new();
+        storage.snapshot_to_writer(&mut snapshot).unwrap();
+
+        let count = Arc::default();
+        let loaded = BucketStorage::load_from_reader(
+            drives,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+            storage.config.clone(),
+            &mut snapshot.as_slice(),
+        )
+        .unwrap();
+        assert_eq!(loaded.capacity(), storage.capacity());
+        assert!(loaded.config.checksums);
+        assert_eq!(loaded.cell_size, storage.cell_size);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            loaded.allocated_indices().collect::<Vec<_>>(),
+            storage.allocated_indices().collect::<Vec<_>>()
+        );
+        for ix in storage.allocated_indices() {
+            assert_eq!(
+                loaded.get_cell_slice::<u64>(ix, num_elems),
+                storage.get_cell_slice::<u64>(ix, num_elems)
+            );
+        }
+
+        let failed_count = Arc::<AtomicU64>::default();
+        let load = |snapshot: &[u8]| {
+            BucketStorage::load_from_reader(
+                Arc::new(vec![tmpdir.path().to_path_buf()]),
+                1,
+                Arc::default(),
+                Arc::clone(&failed_count),
+                BucketStorageConfig::default(),
+                &mut &snapshot[..],
+            )
+        };
+        // a truncated snapshot is an error
+        assert!(matches!(
+            load(&snapshot[..snapshot.len() - 1]),
+            Err(BucketStorageError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        let files = || std::fs::read_dir(tmpdir.path()).unwrap().count();
+        let files_before = files();
+        // corrupted sizes are rejected before anything is allocated
+        let corrupt = |offset: usize, value: u64| {
+            let mut corrupted = snapshot.clone();
+            corrupted[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            load(&corrupted)
+        };
+        assert!(matches!(
+            corrupt(0, u64::MAX),
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2: 4 })
+        ));
+        assert!(matches!(
+            corrupt(0, u64::MAX >> 4),
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2: 4 })
+        ));
+        assert!(matches!(
+            corrupt(8, 63),
+            Err(BucketStorageError::CapacityTooLarge { .. })
+        ));
+        assert!(matches!(
+            corrupt(8, u64::MAX),
+            Err(BucketStorageError::CapacityTooLarge { .. })
+        ));
+        assert!(matches!(
+            corrupt(16, 17),
+            Err(BucketStorageError::BadHeader)
+        ));
+        // as is a cell index beyond the capacity
+        assert!(matches!(
+            corrupt(24, 16),
+            Err(BucketStorageError::IndexOutOfBounds { ix: 16, .. })
+        ));
+        // the storage created for it is removed again, and the cells loaded before are not counted
+        assert_eq!(files(), files_before);
+        assert_eq!(failed_count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_bucket_storage_compact() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let count = Arc::default();
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, std::mem::size_of::<u64>() as u64)
+            .capacity_pow2(4)
+            .count(Arc::clone(&count))
+            .config(BucketStorageConfig {
+                checksums: true,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(storage.compact(), None);
+
+        let live = [1, 4, 5, 9, 15];
+        for ix in live {
+            storage.allocate(ix, false).unwrap();
+            storage.write_checked(ix, ix + 100);
+        }
+        storage.allocate_with_uid(12, 7).unwrap();
+        storage.write_checked(12, 112u64);
+
+        let highest = storage.compact();
+        assert_eq!(highest, Some(5));
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            (0..=5).collect::<Vec<_>>()
+        );
+        assert_eq!(count.load(Ordering::Relaxed), 6);
+        let mut values = storage
+            .iter_cells::<u64>()
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!(values, vec![101, 104, 105, 109, 112, 115]);
+        // moved cells and the cells they were moved from have valid checksums
+        assert!((0..=5).chain([9, 12, 15]).all(|ix| storage.verify(ix)));
+        // the owner of a moved cell moves with it
+        let moved = (0..=5).find(|ix| *storage.get::<u64>(*ix) == 112).unwrap();
+        assert_eq!(storage.uid(moved), Some(7));
+
+        // already compact
+        assert_eq!(storage.compact(), Some(5));
+    }
+
+    #[test]
+    fn test_bucket_storage_flush_policy() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let new_storage = |flush_policy, stats: &Arc<BucketStats>| {
+            BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .stats(Arc::clone(stats))
+                .config(BucketStorageConfig {
+                    flush_policy,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        let stats = Arc::default();
+        let mut storage = new_storage(FlushPolicy::EveryNWrites(3), &stats);
+        storage.allocate(0, false).unwrap();
+        *storage.get_mut::<u64>(0) = 1;
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 0);
+        storage.allocate(1, false).unwrap();
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 1);
+        // failed operations and reads are not writes
+        assert!(storage.allocate(1, false).is_err());
+        storage.get::<u64>(0);
+        storage.free(1);
+        storage.get_mut_cell_slice::<u64>(0, 1)[0] = 2;
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 1);
+        storage.free(0);
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 2);
+        // a batch counts every cell it allocates, but flushes once
+        storage.allocate_batch(&[0, 1]).unwrap();
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 2);
+        storage.allocate_batch(&[2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 3);
+        assert!(storage.allocate_batch(&[7, 0]).is_err());
+        storage.allocate(7, false).unwrap();
+        assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 3);
+
+        for flush_policy in [FlushPolicy::Never, FlushPolicy::OnDrop] {
+            let stats = Arc::default();
+            let mut storage = new_storage(flush_policy, &stats);
+            (0..storage.capacity()).for_each(|ix| {
+                storage.allocate(ix, false).unwrap();
+                *storage.get_mut::<u64>(ix) = ix;
+            });
+            assert_eq!(stats.policy_flushes.load(Ordering::Relaxed), 0);
+            let path = storage.into_persisted();
+            assert!(path.exists());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bucket_storage_numa_node() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        // node 0 exists on every machine, the others don't exist anywhere.
+        // Binding is best effort, so the storage is usable either way.
+        for numa_node in [0, 1000, u32::MAX] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    numa_node: Some(numa_node),
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            storage.allocate(1, false).unwrap();
+            *storage.get_mut::<u64>(1) = 5;
+            assert_eq!(*storage.get::<u64>(1), 5);
+            storage.grow_in_place(DEFAULT_CAPACITY_POW2 + 1).unwrap();
+            assert_eq!(*storage.get::<u64>(1), 5);
+        }
+        assert!(bind_to_numa_node(&[], 1000).is_ok());
+    }
+
+    #[test]
+    fn test_bucket_storage_reserve() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let stats = Arc::<BucketStats>::default();
+        let count = Arc::default();
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            2,
+            1,
+            Arc::clone(&stats),
+            Arc::clone(&count),
+        );
+        storage.allocate(0, false).unwrap();
+        *storage.get_mut::<u64>(0) = 3;
+        // 2 cells fit in 4 at half occupancy
+        assert!(!storage.reserve(1).unwrap());
+        assert_eq!(stats.resizes.load(Ordering::Relaxed), 0);
+
+        let old_path = storage.file_path().unwrap().to_path_buf();
+        assert!(storage.reserve(20).unwrap());
+        assert_eq!(stats.resizes.load(Ordering::Relaxed), 1);
+        assert_eq!(storage.capacity(), 64);
+        assert!(!old_path.exists());
+        assert_eq!(
+            storage.iter_cells::<u64>().collect::<Vec<_>>(),
+            vec![(0, &3)]
+        );
+
+        // the reserved cells are allocated without growing again
+        let batch = (1..=20).collect::<Vec<_>>();
+        storage.allocate_batch(&batch).unwrap();
+        assert!(!storage.reserve(0).unwrap());
+        assert_eq!(count.load(Ordering::Relaxed), 21);
+        assert_eq!(stats.resizes.load(Ordering::Relaxed), 1);
+        assert_eq!(storage.capacity(), 64);
+
+        // beyond MAX_CAPACITY_POW2, rather than clamped to a capacity that cannot hold them
+        assert!(matches!(
+            storage.reserve(1 << (MAX_CAPACITY_POW2 - 1)),
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2 })
+                if capacity_pow2 == MAX_CAPACITY_POW2 + 1
+        ));
+        assert_eq!(storage.capacity(), 64);
+    }
+
+    #[test]
+    fn test_bucket_storage_close() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let stats = Arc::<BucketStats>::default();
+        for persist_on_drop in [false, true] {
+            let mut storage = BucketStorage::new(
+                Arc::clone(&drives),
+                1,
+                std::mem::size_of::<u64>() as u64,
+                1,
+                Arc::clone(&stats),
+                Arc::default(),
+            );
+            storage.allocate(0, false).unwrap();
+            *storage.get_mut::<u64>(0) = 1;
+            storage.persist_on_drop = persist_on_drop;
+            let path = storage.file_path().unwrap().to_path_buf();
+            storage.close().unwrap();
+            assert_eq!(path.exists(), persist_on_drop);
+            if persist_on_drop {
+                std::fs::remove_file(path).unwrap();
+            } else {
+                assert_eq!(stats.net_bytes_on_disk(), 0);
+            }
+        }
+    }
+
+    #[repr(align(16))]
+    struct OverAligned([u64; 2]);
+
+    #[test]
+    fn test_alignment_check() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<OverAligned>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(storage.is_aligned_for::<u64>());
+        // the 8 byte header puts the payload 8 bytes past a 16 byte boundary
+        assert!(!storage.is_aligned_for::<OverAligned>());
+    }
+
+    #[test]
+    #[should_panic(expected = "cell is not aligned for the requested type")]
+    fn test_get_misaligned_panics() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<OverAligned>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        storage.get::<OverAligned>(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not aligned for the cell type")]
+    fn test_typed_bucket_storage_misaligned() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        TypedBucketStorage::<OverAligned>::new_with_capacity(
+            Arc::new(paths),
+            std::mem::size_of::<OverAligned>() as u64,
+            DEFAULT_CAPACITY_POW2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+    }
+
+    #[test]
+    fn test_capacity_too_large() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let stats = Arc::<BucketStats>::default();
+        let result = BucketStorage::try_new_with_capacity(
+            Arc::clone(&drives),
+            1,
+            1,
+            40,
+            1,
+            Arc::clone(&stats),
+            Arc::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2: 40 })
+        ));
+        // nothing was allocated
+        assert_eq!(stats.bytes_allocated.load(Ordering::Relaxed), 0);
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+
+        let config = BucketStorageConfig {
+            max_file_bytes: Some(1 << 12),
+            ..BucketStorageConfig::default()
+        };
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::clone(&drives))
+            .elem(1, 8)
+            .stats(Arc::clone(&stats))
+            .config(config.clone())
+            .build()
+            .unwrap();
+        // 2^8 cells of 16 bytes plus the prologue exceed 4k
+        assert!(matches!(
+            storage.grow_in_place(8),
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2: 8 })
+        ));
+        assert_eq!(storage.capacity_pow2, DEFAULT_CAPACITY_POW2);
+        storage.grow_in_place(7).unwrap();
+        assert!(matches!(
+            BucketStorageBuilder::default()
+                .drives(drives)
+                .elem(1, 8)
+                .capacity_pow2(9)
+                .stats(stats)
+                .config(config)
+                .build(),
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2: 9 })
+        ));
+    }
+
+    #[test]
+    fn test_new_in_arena() {
+        let tmpdir = tempdir().unwrap();
+        let arena = Arc::new(BucketArena::new(tmpdir.path(), 1 << 16).unwrap());
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let new_storage = || {
+            BucketStorageBuilder::default()
+                .elem(1, elem_size)
+                .build_in_arena(&arena)
+        };
+        let mut storages = (0..4).map(|_| new_storage().unwrap()).collect::<Vec<_>>();
+        // every storage is backed by the one arena file
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+        for (i, storage) in storages.iter_mut().enumerate() {
+            assert!(storage.is_free(0));
+            storage.allocate(0, false).unwrap();
+            *storage.get_mut::<u64>(0) = i as u64;
+        }
+        // storages do not see each other's cells
+        for (i, storage) in storages.iter().enumerate() {
+            assert_eq!(storage.allocated_indices().collect::<Vec<_>>(), vec![0]);
+            assert_eq!(*storage.get::<u64>(0), i as u64);
+        }
+        assert!(storages[0]
+            .grow_in_place(DEFAULT_CAPACITY_POW2 + 1)
+            .is_err());
+
+        let free_bytes = arena.free_bytes();
+        storages.remove(1);
+        assert!(arena.free_bytes() > free_bytes);
+        // the reclaimed region comes back zeroed
+        let storage = new_storage().unwrap();
+        assert!(storage.is_free(0));
+        assert_eq!(*storage.get::<u64>(0), 0);
+        for (i, storage) in [0, 2, 3].into_iter().zip(&storages) {
+            assert_eq!(*storage.get::<u64>(0), i);
+        }
+
+        let mut rest = vec![];
+        while let Ok(storage) = new_storage() {
+            rest.push(storage);
+        }
+        assert!(matches!(new_storage(), Err(BucketStorageError::ArenaFull)));
+    }
+
+    #[test]
+    fn test_audit_count() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
+        storage.allocate(0, false).unwrap();
+        storage.allocate(1, false).unwrap();
+        assert_eq!(storage.audit_count(), 2);
+        // lock and unlock headers without going through allocate or free
+        assert!(storage.header_ptr(2).try_lock());
+        assert!(storage.header_ptr(3).try_lock());
+        storage.header_ptr(0).unlock();
+        assert_eq!(storage.count.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.audit_count(), 3);
+        storage.reconcile_count();
+        assert_eq!(storage.count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_drive_free_bytes() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
+        let free_bytes = storage.drive_free_bytes().unwrap();
+        // the test machine has some space left on its temp drive
+        assert!(free_bytes > 0);
+        assert!(free_bytes < u64::MAX);
+    }
+
+    #[test]
+    fn test_relocate_to() {
+        let tmpdirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let drives = tmpdirs
+            .iter()
+            .map(|tmpdir| Arc::new(vec![tmpdir.path().to_path_buf()]))
+            .collect::<Vec<_>>();
+        let count = Arc::<AtomicU64>::default();
+        let mut storage = BucketStorage::new(
+            Arc::clone(&drives[0]),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+        );
+        for ix in [1, 4, 7] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix * 10;
+        }
+        let old_path = storage.file_path().unwrap().to_path_buf();
+        storage.relocate_to(&drives[1]).unwrap();
+        assert!(!old_path.exists());
+        assert!(storage.file_path().unwrap().exists());
+        assert_eq!(storage.drive(), Some(tmpdirs[1].path()));
+        assert_eq!(storage.capacity_pow2, DEFAULT_CAPACITY_POW2);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            storage.allocated_indices().collect::<Vec<_>>(),
+            vec![1, 4, 7]
+        );
+        for ix in [1, 4, 7] {
+            assert_eq!(*storage.get::<u64>(ix), ix * 10);
+        }
+        // the relocated storage is still usable
+        storage.free(4);
+        storage.allocate(5, false).unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_lock_cell_mut() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        unsafe {
+            let mut first = storage.lock_cell_mut::<u64>(0).unwrap();
+            assert!(storage.lock_cell_mut::<u64>(0).is_none());
+            let mut second = storage.lock_cell_mut::<u64>(1).unwrap();
+            *first = 1;
+            *second = 2;
+            assert!(storage.lock_cell_mut::<u64>(storage.capacity()).is_none());
+            drop(first);
+            assert_eq!(*storage.lock_cell_mut::<u64>(0).unwrap(), 1);
+            assert_eq!(*second, 2);
+        }
+    }
+
+    #[test]
+    fn test_par_allocated_indices() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        // several chunks of cells
+        let capacity_pow2 = PARALLEL_SCAN_CHUNK_CELLS.trailing_zeros() as u8 + 2;
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            1,
+            capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(storage.capacity_cells_range(), 0..storage.capacity());
+        assert_eq!(storage.par_allocated_indices().count(), 0);
+        for ix in (0..storage.capacity()).step_by(7) {
+            storage.allocate(ix, false).unwrap();
+        }
+        storage.allocate(storage.capacity() - 1, false).unwrap();
+        let mut parallel = storage.par_allocated_indices().collect::<Vec<_>>();
+        parallel.sort_unstable();
+        assert_eq!(parallel, storage.allocated_indices().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_write_sampling() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        for (rate, expected) in [(0, 0), (1, 4), (2, 2)] {
+            let stats = Arc::<BucketStats>::default();
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .stats(Arc::clone(&stats))
+                .config(BucketStorageConfig {
+                    write_sample_rate: rate,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            for ix in 0..3 {
+                *storage.get_mut::<u64>(ix) = ix;
+            }
+            storage.get_mut_cell_slice::<u64>(3, 1)[0] = 3;
+            assert_eq!(stats.write_sample_count.load(Ordering::Relaxed), expected);
+            assert!(
+                stats.write_sample_max_us.load(Ordering::Relaxed)
+                    <= stats.write_sample_us.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_bitmap_allocated_flags() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let cells = [0, 3, 31];
+        let mut results = vec![];
+        for (allocated_flag_location, checksums) in [
+            (IsAllocatedFlagLocation::InHeader, false),
+            (IsAllocatedFlagLocation::Bitmap, false),
+            (IsAllocatedFlagLocation::Bitmap, true),
+        ] {
+            let config = BucketStorageConfig {
+                allocated_flag_location,
+                checksums,
+                ..BucketStorageConfig::default()
+            };
+            let count = Arc::<AtomicU64>::default();
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, elem_size)
+                .count(Arc::clone(&count))
+                .config(config.clone())
+                .build()
+                .unwrap();
+            assert!(storage.is_aligned_for::<u64>());
+            let write = |storage: &mut BucketStorage, ix: u64, value: u64| {
+                if checksums {
+                    storage.write_checked(ix, value);
+                } else {
+                    *storage.get_mut(ix) = value;
+                }
+            };
+            for ix in cells {
+                storage.allocate(ix, false).unwrap();
+                write(&mut storage, ix, ix * 10);
+            }
+            assert!(matches!(
+                storage.allocate(3, false),
+                Err(BucketStorageError::AlreadyAllocated)
+            ));
+            assert!(matches!(
+                storage.allocate_batch(&[1, 3]),
+                Err(BucketStorageError::AlreadyAllocated)
+            ));
+            assert!(storage.is_free(1));
+            storage.free(3);
+            assert!(storage.is_free(3));
+            storage.allocate(3, false).unwrap();
+            write(&mut storage, 3, 30);
+            assert_eq!(storage.uid(3), Some(UID_LOCKED));
+            assert!(!checksums || cells.into_iter().all(|ix| storage.verify(ix)));
+
+            let resized = BucketStorage::new_resized(
+                Some(&drives),
+                1,
+                Some(&storage),
+                DEFAULT_CAPACITY_POW2 + 1,
+                1,
+                elem_size,
+                &Arc::default(),
+            );
+            let allocated = resized
+                .allocated_indices()
+                .map(|ix| (ix, *resized.get::<u64>(ix)))
+                .collect::<Vec<_>>();
+            assert_eq!(count.load(Ordering::Relaxed), cells.len() as u64);
+            results.push(allocated);
+
+            match allocated_flag_location {
+                IsAllocatedFlagLocation::InHeader => {
+                    assert_eq!(storage.cell_size, elem_size + 8);
+                }
+                IsAllocatedFlagLocation::Bitmap => {
+                    assert_eq!(
+                        storage.cell_size,
+                        elem_size + if checksums { CHECKSUM_SIZE as u64 } else { 0 }
+                    );
+                    assert!(matches!(
+                        storage.allocate_with_uid(4, 5),
+                        Err(BucketStorageError::UidsUnsupported)
+                    ));
+                    // the bitmap grows, so the cells are copied to a new file at the same indices
+                    let before = storage.iter_cells::<u64>().map(|(ix, value)| (ix, *value));
+                    let before = before.collect::<Vec<_>>();
+                    let old_path = storage.file_path().unwrap().to_path_buf();
+                    storage.grow_in_place(DEFAULT_CAPACITY_POW2 + 1).unwrap();
+                    assert_eq!(storage.capacity_pow2(), DEFAULT_CAPACITY_POW2 + 1);
+                    assert!(!old_path.exists());
+                    assert_eq!(
+                        storage
+                            .iter_cells::<u64>()
+                            .map(|(ix, value)| (ix, *value))
+                            .collect::<Vec<_>>(),
+                        before
+                    );
+                    assert!(!checksums || cells.into_iter().all(|ix| storage.verify(ix)));
+                    assert_eq!(count.load(Ordering::Relaxed), cells.len() as u64);
+                }
+            }
+            assert_eq!(storage.compact(), Some(2));
+            assert_eq!(
+                storage
+                    .iter_cells::<u64>()
+                    .map(|(ix, value)| (ix, *value))
+                    .collect::<Vec<_>>(),
+                vec![(0, 0), (1, 310), (2, 30)]
+            );
+            storage.clear().unwrap();
+            assert_eq!(storage.allocated_indices().count(), 0);
+        }
+        assert_eq!(results[0], vec![(0, 0), (6, 30), (62, 310)]);
+        assert!(results.iter().all(|result| result == &results[0]));
+    }
+
+    #[test]
+    fn test_verify_layout() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        assert_eq!(storage.verify_layout(), Ok(()));
+
+        storage.mmap = Mapping::Mmap(MmapMut::map_anon(PROLOGUE_SIZE + 100).unwrap());
+        let err = storage.verify_layout().unwrap_err();
+        assert!(err.contains("bytes need"), "{err}");
+
+        storage.cell_size = 4;
+        let err = storage.verify_layout().unwrap_err();
+        assert!(err.contains("smaller than the header size"), "{err}");
+    }
+
+    #[test]
+    fn test_snapshot_cow() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        for ix in [1, 2] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix;
+        }
+        let snapshot = storage.snapshot_cow().unwrap();
+        assert!(snapshot.is_read_only());
+        assert_ne!(snapshot.file_path(), storage.file_path());
+
+        *storage.get_mut::<u64>(1) = 10;
+        storage.free(2);
+        storage.allocate(3, false).unwrap();
+        assert_eq!(
+            snapshot
+                .iter_cells::<u64>()
+                .map(|(ix, value)| (ix, *value))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2)]
+        );
+        assert_eq!(snapshot.count.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.count.load(Ordering::Relaxed), 2);
+
+        // each removes its own file
+        let path = storage.file_path().unwrap().to_path_buf();
+        let snapshot_path = snapshot.file_path().unwrap().to_path_buf();
+        assert_eq!(snapshot_path.parent(), path.parent());
+        drop(snapshot);
+        assert!(!snapshot_path.exists());
+        assert!(path.exists());
+        drop(storage);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_try_reserve_file() {
+        let tmpdir = tempdir().unwrap();
+        let cell_size = BucketStorage::cell_size_for(8, 1);
+        let path = BucketStorage::try_reserve_file(tmpdir.path(), cell_size, 4).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (PROLOGUE_SIZE as u64) + 16 * cell_size
+        );
+        // the reserved file can be adopted
+        let count = Arc::<AtomicU64>::default();
+        let mut storage = BucketStorage::open_existing(
+            path,
+            cell_size,
+            4,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        storage.allocate(15, false).unwrap();
+        storage.persist_on_drop = false;
+        drop(storage);
+
+        let missing = tmpdir.path().join("missing");
+        let err = BucketStorage::try_reserve_file(&missing, cell_size, 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = BucketStorage::try_reserve_file(tmpdir.path(), u64::MAX, MAX_CAPACITY_POW2)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = BucketStorage::try_reserve_file(tmpdir.path(), cell_size, MAX_CAPACITY_POW2 + 1)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // failures leave nothing behind
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_stats_summary() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let stats = Arc::<BucketStats>::default();
+        let mut storage = BucketStorage::new_resized(Some(&drives), 1, None, 2, 1, 8, &stats);
+        storage.allocate(0, false).unwrap();
+        let storage = BucketStorage::new_resized(Some(&drives), 1, Some(&storage), 3, 1, 8, &stats);
+        assert!(Arc::ptr_eq(storage.stats(), &stats));
+        let summary = storage.stats_summary();
+        assert!(
+            summary.starts_with("occupancy: 0.125, count: 1, capacity: 8, resizes: 1,"),
+            "{summary}"
+        );
+        storage.log_stats();
+    }
+
+    #[test]
+    fn test_raw_parts() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let count = Arc::<AtomicU64>::default();
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+        );
+        storage.allocate(3, false).unwrap();
+        *storage.get_mut::<u64>(3) = 42;
+        let parts = storage.into_raw_parts().ok().unwrap();
+        // the file outlives the storage
+        assert!(parts.path.exists());
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        let storage = BucketStorage::from_raw_parts(
+            parts,
+            1,
+            Arc::default(),
+            Arc::clone(&count),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(storage.allocated_indices().collect::<Vec<_>>(), vec![3]);
+        assert_eq!(*storage.get::<u64>(3), 42);
+        let mut parts = storage.into_raw_parts().ok().unwrap();
+        let path = parts.path.clone();
+        parts.cell_size += 8;
+        assert!(matches!(
+            BucketStorage::from_raw_parts(
+                parts,
+                1,
+                Arc::default(),
+                count,
+                BucketStorageConfig::default(),
+            ),
+            Err(BucketStorageError::BadHeader)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_len() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
+        assert!(storage.is_empty());
+        storage.allocate(0, false).unwrap();
+        storage.allocate(1, false).unwrap();
+        assert_eq!(storage.len(), 2);
+        assert!(!storage.is_empty());
+        storage.free(0);
+        assert_eq!(storage.len(), 1);
+        storage.free(1);
+        assert_eq!(storage.len(), 0);
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        for ix in [2, 5] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix;
+        }
+        let duplicate = storage.duplicate().unwrap();
+        assert_ne!(duplicate.file_path(), storage.file_path());
+        assert_eq!(duplicate.drive(), storage.drive());
+        assert!(!Arc::ptr_eq(&duplicate.count, &storage.count));
+        assert!(!Arc::ptr_eq(duplicate.stats(), storage.stats()));
+
+        *storage.get_mut::<u64>(2) = 20;
+        storage.free(5);
+        storage.allocate(6, false).unwrap();
+        assert_eq!(duplicate.len(), 2);
+        assert_eq!(
+            duplicate
+                .iter_cells::<u64>()
+                .map(|(ix, value)| (ix, *value))
+                .collect::<Vec<_>>(),
+            vec![(2, 2), (5, 5)]
+        );
+
+        // each storage removes only its own file
+        let path = duplicate.file_path().unwrap().to_path_buf();
+        drop(duplicate);
+        assert!(!path.exists());
+        assert!(storage.file_path().unwrap().exists());
+    }
+
+    #[test]
+    fn test_release_range() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            12,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        storage.allocate(0, false).unwrap();
+        *storage.get_mut::<u64>(0) = 1;
+        // releasing discards the contents of the released cells, which is intended because they are free
+        for ix in 1..1000 {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix;
+            storage.free(ix);
+        }
+        storage.release_range(1, 999).unwrap();
+        // empty and out of range requests do nothing
+        storage.release_range(1, 0).unwrap();
+        storage.release_range(storage.capacity(), 10).unwrap();
+        // cells next to the range are untouched
+        assert_eq!(*storage.get::<u64>(0), 1);
+        assert!(storage.is_free(1));
+        storage.allocate(1, false).unwrap();
+    }
+
+    #[test]
+    fn test_len_acquire() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let config = BucketStorageConfig {
+            count_ordering: CountOrdering::AcqRel,
+            ..BucketStorageConfig::default()
+        };
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let mut writer = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, elem_size)
+            .capacity_pow2(8)
+            .config(config.clone())
+            .build()
+            .unwrap();
+        // a second mapping of the same file, counted by the same 'count'
+        let reader = BucketStorage::open_readonly(
+            writer.file_path().unwrap().to_path_buf(),
+            writer.cell_size,
+            writer.capacity_pow2,
+            1,
+            Arc::default(),
+            Arc::clone(&writer.count),
+            config,
+        )
+        .unwrap();
+        let cells = writer.capacity();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for ix in 0..cells {
+                    // written before the allocation that publishes it
+                    *writer.get_mut::<u64>(ix) = ix + 1;
+                    writer.allocate(ix, false).unwrap();
+                }
+            });
+            let mut seen = 0;
+            while seen < cells {
+                let len = reader.len_acquire();
+                assert!(len >= seen);
+                for ix in seen..len {
+                    assert_eq!(*reader.get::<u64>(ix), ix + 1);
+                }
+                seen = len;
+            }
+        });
+        assert_eq!(writer.len(), cells);
+    }
+
+    #[test]
+    fn test_fit_pow2() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
+        for (len, expected) in [
+            (0, DEFAULT_CAPACITY_POW2),
+            (1, DEFAULT_CAPACITY_POW2),
+            (16, DEFAULT_CAPACITY_POW2),
+            (17, 6),
+            (32, 6),
+            (33, 7),
+            (1 << 20, 21),
+            (u64::MAX / 4, MAX_CAPACITY_POW2),
+        ] {
+            storage.count.store(len, Ordering::Relaxed);
+            let fit = storage.fit_pow2();
+            assert_eq!(fit, expected, "{len}");
+            if fit < MAX_CAPACITY_POW2 {
+                assert!(len as f64 / (1u64 << fit) as f64 <= FIT_LOAD_FACTOR);
+            }
+        }
+    }
+
+    #[test]
+    fn test_contents_checksum() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let (cell_size, capacity_pow2) = (storage.cell_size, storage.capacity_pow2);
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 42;
+        let offset = storage.cell_offset(ix) + storage.header_size();
+        let path = storage.file_path().unwrap().to_path_buf();
+        storage.persist_on_drop = true;
+        storage.close().unwrap();
+
+        let open = || {
+            BucketStorage::open_readonly(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+        };
+        assert_eq!(*open().unwrap().get::<u64>(ix), 42);
+
+        // flip a bit of the cell in the closed file
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[offset] ^= 1;
+        std::fs::write(&path, contents).unwrap();
+        assert!(matches!(open(), Err(BucketStorageError::ChecksumMismatch)));
+        assert!(matches!(
+            BucketStorage::open_existing(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            ),
+            Err(BucketStorageError::ChecksumMismatch)
+        ));
+        // the file is left for the caller to discard
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_contents_checksum_cleared_while_open() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        let (cell_size, capacity_pow2) = (storage.cell_size, storage.capacity_pow2);
+        let path = storage.into_persisted();
+        let recorded = || {
+            let contents = std::fs::read(&path).unwrap();
+            FilePrologue::read_contents_checksum(&contents).is_some()
+        };
+        assert!(recorded());
+
+        let open = || {
+            BucketStorage::open_existing(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+            .unwrap()
+        };
+        let mut storage = open();
+        // cleared on disk as soon as the file is open for writing
+        assert!(!recorded());
+        storage.allocate(3, false).unwrap();
+        *storage.get_mut::<u64>(3) = 42;
+        // a crash: the storage is never closed
+        std::mem::forget(storage);
+
+        let storage = open();
+        assert_eq!(*storage.get::<u64>(3), 42);
+        drop(storage);
+        assert!(recorded());
+    }
+
+    #[test]
+    fn test_background_resize() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        for allocated_flag_location in [
+            IsAllocatedFlagLocation::InHeader,
+            IsAllocatedFlagLocation::Bitmap,
+        ] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::new(paths.clone()))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    allocated_flag_location,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            for ix in 0..10 {
+                storage.allocate(ix, false).unwrap();
+                *storage.get_mut::<u64>(ix) = ix + 100;
+            }
+            let increment = 2;
+            let pending = storage
+                .begin_resize(storage.capacity_pow2 + increment)
+                .unwrap();
+            // the storage is still usable while the resize is pending
+            for ix in 0..10 {
+                assert_eq!(*storage.get::<u64>(ix), ix + 100);
+            }
+            *storage.get_mut::<u64>(2) = 42;
+            storage.free(5);
+            storage.allocate(20, false).unwrap();
+            *storage.get_mut::<u64>(20) = 120;
+
+            let resized = storage.finish_resize(pending);
+            assert_eq!(resized.capacity_pow2, DEFAULT_CAPACITY_POW2 + increment);
+            assert_eq!(resized.len(), 10);
+            assert_eq!(resized.allocated_indices().count(), 10);
+            let expected = |ix| match ix {
+                2 => Some(42),
+                5 => None,
+                20 => Some(120),
+                ix if ix < 10 => Some(ix + 100),
+                _ => None,
+            };
+            for ix in 0..1 << DEFAULT_CAPACITY_POW2 {
+                let new_ix = ix << increment;
+                match expected(ix) {
+                    Some(value) => {
+                        assert!(!resized.is_free(new_ix), "{ix}");
+                        assert_eq!(*resized.get::<u64>(new_ix), value);
+                    }
+                    None => assert!(resized.is_free(new_ix), "{ix}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_begin_resize_does_not_wait_for_the_copy() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        storage.allocate(1, false).unwrap();
+        *storage.get_mut::<u64>(1) = 10;
+        let (start_copy, copy_allowed) = std::sync::mpsc::channel();
+        let pending = storage
+            .begin_resize_with(DEFAULT_CAPACITY_POW2 + 1, move || {
+                copy_allowed.recv().unwrap();
+            })
+            .unwrap();
+        // the copy cannot start until it is told to, so it did not happen in begin_resize
+        assert!(!pending.is_finished());
+        let files = || std::fs::read_dir(tmpdir.path()).unwrap().count();
+        assert_eq!(files(), 1);
+        assert_eq!(*storage.get::<u64>(1), 10);
+        *storage.get_mut::<u64>(1) = 11;
+
+        start_copy.send(()).unwrap();
+        let resized = storage.finish_resize(pending);
+        assert_eq!(*resized.get::<u64>(1 << 1), 11);
+        assert_eq!(files(), 1);
+    }
+
+    #[test]
+    fn test_unchecked_access() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        for allocated_flag_location in [
+            IsAllocatedFlagLocation::InHeader,
+            IsAllocatedFlagLocation::Bitmap,
+        ] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::new(paths.clone()))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    allocated_flag_location,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            for ix in (0..storage.capacity()).step_by(3) {
+                storage.allocate(ix, false).unwrap();
+                *storage.get_mut::<u64>(ix) = ix * 10 + 1;
+            }
+            for ix in 0..storage.capacity() {
+                unsafe {
+                    assert_eq!(storage.is_free_unchecked(ix), storage.is_free(ix));
+                    assert_eq!(storage.get_unchecked::<u64>(ix), storage.get::<u64>(ix));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_growth_history() {
+        let tmpdir = tempdir().unwrap();
+        let drives = Arc::new(vec![tmpdir.path().to_path_buf()]);
+        let stats = Arc::<BucketStats>::default();
+        let first_pow2 = 2;
+        let mut bucket =
+            BucketStorage::new_resized(Some(&drives), 1, None, first_pow2, 1, 8, &stats);
+        assert!(stats.growth_history().is_empty());
+        let resizes = GROWTH_HISTORY_LEN as u8 + 2;
+        for capacity_pow2 in first_pow2 + 1..=first_pow2 + resizes {
+            bucket =
+                BucketStorage::new_resized(None, 1, Some(&bucket), capacity_pow2, 1, 8, &stats);
+        }
+        let history = stats.growth_history();
+        assert_eq!(history.len(), GROWTH_HISTORY_LEN);
+        // only the most recent resizes are kept, oldest first
+        let newest_pow2 = first_pow2 + resizes;
+        for (record, new_pow2) in history
+            .iter()
+            .zip(newest_pow2 + 1 - GROWTH_HISTORY_LEN as u8..=newest_pow2)
+        {
+            assert_eq!(record.old_capacity_pow2, new_pow2 - 1);
+            assert_eq!(record.new_capacity_pow2, new_pow2);
+        }
+        assert!(history
+            .windows(2)
+            .all(|records| records[0].timestamp_us <= records[1].timestamp_us));
+    }
+
+    #[test]
+    fn test_try_allocate_any() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        let taken = 3;
+        storage.allocate(taken, false).unwrap();
+        let mut allocated = HashSet::new();
+        while let Some(ix) = storage.try_allocate_any() {
+            assert!(allocated.insert(ix), "{ix} was allocated twice");
+            assert!(!storage.is_free(ix));
+        }
+        assert!(!allocated.contains(&taken));
+        assert_eq!(allocated.len() as u64, storage.capacity() - 1);
+        assert_eq!(storage.len(), storage.capacity());
+        assert_eq!(storage.try_allocate_any(), None);
+
+        // a freed cell is found again, wherever the scan starts
+        let ix = 1;
+        storage.free(ix);
+        assert_eq!(storage.try_allocate_any(), Some(ix));
+        assert_eq!(storage.try_allocate_any(), None);
+
+        // nothing can be allocated once the file is removed, rather than panicking
+        storage.free(ix);
+        storage.remove_file_now().unwrap();
+        assert_eq!(storage.try_allocate_any(), None);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let stats = Arc::<BucketStats>::default();
+        let small = BucketStorage::new(
+            Arc::clone(&drives),
+            1,
+            8,
+            1,
+            Arc::clone(&stats),
+            Arc::default(),
+        );
+        small.warmup().unwrap();
+        assert_eq!(stats.warmup_us.load(Ordering::Relaxed), 0);
+
+        let capacity_pow2 = (4 * WARMUP_MIN_BYTES).trailing_zeros() as u8 - 4;
+        let mut storage = BucketStorage::new_with_capacity(
+            drives,
+            1,
+            8,
+            capacity_pow2,
+            1,
+            Arc::clone(&stats),
+            Arc::default(),
+        );
+        assert!(storage.mmap_len() >= WARMUP_MIN_BYTES);
+        storage.allocate(7, false).unwrap();
+        *storage.get_mut::<u64>(7) = 42;
+        let path = storage.into_persisted();
+        let storage = BucketStorage::open_existing(
+            path,
+            16,
+            capacity_pow2,
+            1,
+            Arc::clone(&stats),
+            Arc::default(),
+            BucketStorageConfig::default(),
+        )
+        .unwrap();
+        storage.warmup().unwrap();
+        assert!(stats.warmup_us.load(Ordering::Relaxed) > 0);
+        assert_eq!(*storage.get::<u64>(7), 42);
+    }
+
+    #[test]
+    fn test_retry_on_enomem() {
+        let stats = BucketStats::default();
+        let mut calls = 0;
+        let result = retry_on_enomem(&stats, || {
+            calls += 1;
+            if calls <= 2 {
+                // ENOMEM on unix
+                Err(io::Error::from(io::ErrorKind::OutOfMemory))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(stats.mmap_retries.load(Ordering::Relaxed), 2);
+
+        // other errors are not retried
+        let stats = BucketStats::default();
+        let mut calls = 0;
+        let result: io::Result<()> = retry_on_enomem(&stats, || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::InvalidInput))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(calls, 1);
+        assert_eq!(stats.mmap_retries.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_cell_payload_size() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let num_elems = 5;
+        for checksums in [false, true] {
+            let storage = BucketStorageBuilder::default()
+                .drives(Arc::new(paths.clone()))
+                .elem(num_elems, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    checksums,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            assert_eq!(
+                storage.cell_payload_size() + storage.header_size() as u64,
+                storage.cell_size
+            );
+            assert_eq!(storage.elems_per_cell::<u64>(), num_elems);
+            assert_eq!(storage.elems_per_cell::<u32>(), num_elems * 2);
+            assert_eq!(storage.elems_per_cell::<[u8; 3]>(), num_elems * 8 / 3);
+        }
+    }
+
+    #[test]
+    fn test_get_bytes() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        for checksums in [false, true] {
+            let mut storage = BucketStorageBuilder::default()
+                .drives(Arc::new(paths.clone()))
+                .elem(1, 3 * std::mem::size_of::<u32>() as u64)
+                .config(BucketStorageConfig {
+                    checksums,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap();
+            let ix = 2;
+            *storage.get_mut::<u32>(ix) = 0x0403_0201;
+            let bytes = storage.get_bytes(ix);
+            assert_eq!(bytes.len() as u64, storage.cell_payload_size());
+            // the header is not part of the contents
+            assert_eq!(bytes.len(), 3 * std::mem::size_of::<u32>());
+            assert_eq!(&bytes[..4], &0x0403_0201u32.to_ne_bytes());
+            assert!(bytes[4..].iter().all(|byte| *byte == 0));
+            // the neighboring cells are untouched
+            assert!(storage.get_bytes(ix - 1).iter().all(|byte| *byte == 0));
+            assert!(storage.get_bytes(ix + 1).iter().all(|byte| *byte == 0));
+
+            storage.get_bytes_mut(ix)[4..8].copy_from_slice(&7u32.to_ne_bytes());
+            assert_eq!(storage.get_cell_slice::<u32>(ix, 3), &[0x0403_0201, 7, 0]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "bad index size")]
+    fn test_get_bytes_out_of_bounds() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        storage.get_bytes(storage.capacity());
+    }
+
+    #[test]
+    fn test_new_resized_reuses_drives() {
+        let tmpdirs = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        let other = tempdir().unwrap();
+        let drives = Arc::new(
+            tmpdirs
+                .iter()
+                .map(|tmpdir| tmpdir.path().to_path_buf())
+                .collect::<Vec<_>>(),
+        );
+        let elem_size = std::mem::size_of::<u64>() as u64;
+        let stats = Arc::default();
+        let mut bucket =
+            BucketStorage::new_resized(Some(&drives), 1, None, 2, 1, elem_size, &stats);
+        let ix = 1;
+        bucket.allocate(ix, false).unwrap();
+        *bucket.get_mut::<u64>(ix) = 42;
+        for capacity_pow2 in 3..8 {
+            bucket = BucketStorage::new_resized(
+                None,
+                1,
+                Some(&bucket),
+                capacity_pow2,
+                1,
+                elem_size,
+                &stats,
+            );
+            assert_eq!(bucket.drives(), &drives);
+            let drive = bucket.drive().unwrap();
+            assert!(drives.iter().any(|original| original == drive));
+        }
+        assert_eq!(*bucket.get::<u64>(ix << 5), 42);
+
+        // explicit drives override the drives of the source bucket
+        let other_drives = Arc::new(vec![other.path().to_path_buf()]);
+        let bucket = BucketStorage::new_resized(
+            Some(&other_drives),
+            1,
+            Some(&bucket),
+            8,
+            1,
+            elem_size,
+            &stats,
+        );
+        assert_eq!(bucket.drive(), Some(other.path()));
+        assert_eq!(bucket.drives(), &other_drives);
+    }
+
+    #[test]
+    fn test_next_capacity_pow2() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let drives = Arc::new(paths);
+        let new_storage = |grow_step_pow2| {
+            BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .config(BucketStorageConfig {
+                    grow_step_pow2,
+                    ..BucketStorageConfig::default()
+                })
+                .build()
+                .unwrap()
+        };
+
+        let storage =
+            BucketStorage::new(Arc::clone(&drives), 1, 1, 1, Arc::default(), Arc::default());
+        assert_eq!(storage.next_capacity_pow2(), DEFAULT_CAPACITY_POW2 + 1);
+        assert_eq!(
+            new_storage(0).next_capacity_pow2(),
+            DEFAULT_CAPACITY_POW2 + 1
+        );
+
+        let mut storage = new_storage(2);
+        let next = storage.next_capacity_pow2();
+        assert_eq!(next, DEFAULT_CAPACITY_POW2 + 2);
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 42;
+        let resized = BucketStorage::new_resized(
+            Some(&drives),
+            1,
+            Some(&storage),
+            next,
+            1,
+            std::mem::size_of::<u64>() as u64,
+            &Arc::default(),
+        );
+        assert_eq!(resized.capacity(), storage.capacity() * 4);
+        // the step carries over to the resized storage
+        assert_eq!(resized.next_capacity_pow2(), next + 2);
+        assert_eq!(*resized.get::<u64>(ix * 4), 42);
+
+        // the step never overflows, and a step past the limit is rejected when the storage is created
+        let storage = new_storage(u8::MAX);
+        let next = storage.next_capacity_pow2();
+        assert_eq!(next, u8::MAX);
+        let result = BucketStorageBuilder::default()
+            .drives(Arc::clone(&drives))
+            .elem(1, 1)
+            .capacity_pow2(next)
+            .config(storage.config.clone())
+            .build();
+        assert!(matches!(
+            result,
+            Err(BucketStorageError::CapacityTooLarge { capacity_pow2 }) if capacity_pow2 == u8::MAX
+        ));
+    }
 
     #[test]
-    fn test_bucket_storage() {
+    #[cfg(unix)]
+    fn test_new_file_is_sparse() {
+        use std::os::unix::fs::MetadataExt;
         let tmpdir = tempdir().unwrap();
         let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
-        assert!(!paths.is_empty());
+        let storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, 8)
+            .capacity_pow2(16)
+            .config(BucketStorageConfig {
+                sync_on_create: true,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        let metadata = std::fs::metadata(storage.file_path().unwrap()).unwrap();
+        assert!(metadata.len() > 1 << 20);
+        // only the page holding the prologue may have been written, 'blocks' is in 512 byte units
+        assert!(
+            metadata.blocks() * 512 <= 64 * 1024,
+            "{}",
+            metadata.blocks()
+        );
+    }
+
+    #[test]
+    fn test_mmap_len() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        assert_eq!(
+            storage.mmap_len() as u64,
+            PROLOGUE_SIZE as u64 + storage.capacity() * storage.cell_size
+        );
+        assert_eq!(
+            storage.mmap_len() as u64,
+            PROLOGUE_SIZE as u64 + storage.capacity_bytes()
+        );
+    }
+
+    #[test]
+    fn test_cas_u64() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            2,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        storage.allocate(3, false).unwrap();
+        assert_eq!(storage.cas_u64(3, 8, 1, 2), Err(0));
+        assert_eq!(storage.cas_u64(3, 8, 0, 5), Ok(0));
+        assert_eq!(*storage.get::<u64>(3), 0);
+        assert_eq!(storage.get_cell_slice::<u64>(3, 2)[1], 5);
+
+        let threads = 8;
+        let increments = 1000;
+        let swaps = AtomicU64::default();
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    for _ in 0..increments {
+                        // a failed swap returns the current value, so no plain read of the cell races the swaps
+                        let mut current = 0;
+                        while let Err(observed) = storage.cas_u64(3, 0, current, current + 1) {
+                            current = observed;
+                        }
+                        swaps.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(swaps.load(Ordering::Relaxed), threads * increments);
+        assert_eq!(*storage.get::<u64>(3), threads * increments);
+    }
+
+    #[test]
+    #[should_panic(expected = "is outside the cell")]
+    fn test_cas_u64_outside_cell() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        let _ = storage.cas_u64(0, 8, 0, 1);
+    }
+
+    #[test]
+    fn test_drive_selection_round_robin() {
+        let tmpdirs = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        let drives = Arc::new(
+            tmpdirs
+                .iter()
+                .map(|tmpdir| tmpdir.path().to_path_buf())
+                .collect::<Vec<_>>(),
+        );
+        let stats = Arc::<BucketStats>::default();
+        let config = BucketStorageConfig {
+            drive_selection: DriveSelection::RoundRobin,
+            ..BucketStorageConfig::default()
+        };
+        let storages = (0..30)
+            .map(|_| {
+                BucketStorageBuilder::default()
+                    .drives(Arc::clone(&drives))
+                    .elem(1, std::mem::size_of::<u64>() as u64)
+                    .capacity_pow2(1)
+                    .stats(Arc::clone(&stats))
+                    .config(config.clone())
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for drive in drives.iter() {
+            let on_drive = storages
+                .iter()
+                .filter(|storage| storage.drive() == Some(drive.as_path()))
+                .count();
+            assert_eq!(on_drive, 10);
+        }
+        // consecutive files go to different drives
+        for pair in storages.windows(2) {
+            assert_ne!(pair[0].drive(), pair[1].drive());
+        }
+        assert_eq!(stats.next_drive.load(Ordering::Relaxed), 30);
+    }
 
+    #[test]
+    fn test_set_max_search() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
         let mut storage =
-            BucketStorage::new(Arc::new(paths), 1, 1, 1, Arc::default(), Arc::default());
-        let ix = 0;
-        assert!(storage.is_free(ix));
-        assert!(storage.allocate(ix, false).is_ok());
-        assert!(storage.allocate(ix, false).is_err());
-        assert!(!storage.is_free(ix));
-        storage.free(ix);
-        assert!(storage.is_free(ix));
-        assert!(storage.is_free(ix));
-        assert!(storage.allocate(ix, false).is_ok());
-        assert!(storage.allocate(ix, false).is_err());
-        assert!(!storage.is_free(ix));
-        storage.free(ix);
-        assert!(storage.is_free(ix));
+            BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        assert_eq!(storage.max_search(), 1);
+        storage.set_max_search(8);
+        assert_eq!(storage.max_search(), 8);
+        storage.set_max_search(2);
+        assert_eq!(storage.max_search(), 2);
+    }
+
+    #[test]
+    fn test_export_allocated() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            6,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(storage.export_allocated::<u64>().is_empty());
+        let inserted = [(41, 4100), (3, 300), (17, 1700), (60, 6000)];
+        for (ix, value) in inserted {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = value;
+        }
+        let mut exported = storage.export_allocated::<u64>();
+        exported.sort_unstable();
+        let mut expected = inserted.to_vec();
+        expected.sort_unstable();
+        assert_eq!(exported, expected);
+    }
+
+    #[test]
+    fn test_merge_from() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Arc<Vec<PathBuf>> = Arc::new(vec![tmpdir.path().to_path_buf()]);
+        let new_storage = |capacity_pow2| {
+            BucketStorage::new_with_capacity(
+                Arc::clone(&paths),
+                1,
+                std::mem::size_of::<u64>() as u64,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+            )
+        };
+        let mut dst = new_storage(4);
+        let mut src = new_storage(3);
+        for (storage, entries) in [
+            (&mut dst, [(0, 10), (5, 15)].as_slice()),
+            (&mut src, [(1, 21), (2, 22), (7, 27)].as_slice()),
+        ] {
+            for (ix, value) in entries {
+                storage.allocate(*ix, false).unwrap();
+                *storage.get_mut::<u64>(*ix) = *value;
+            }
+        }
+
+        dst.merge_from(&src).unwrap();
+        assert_eq!(dst.count.load(Ordering::Relaxed), 5);
+        let mut values = dst
+            .export_allocated::<u64>()
+            .into_iter()
+            .map(|(_ix, value)| value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!(values, vec![10, 15, 21, 22, 27]);
+        // the source is unchanged
+        assert_eq!(
+            src.export_allocated::<u64>(),
+            vec![(1, 21), (2, 22), (7, 27)]
+        );
+
+        // not enough room, nothing is merged
+        let mut full = new_storage(2);
+        for ix in 0..3 {
+            full.allocate(ix, false).unwrap();
+        }
+        assert!(matches!(
+            full.merge_from(&src),
+            Err(BucketStorageError::NoFreeCell)
+        ));
+        assert_eq!(full.count.load(Ordering::Relaxed), 3);
+        assert_eq!(full.allocated_indices().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        // cells laid out differently cannot be merged, even with the same cell size
+        let mut bitmap = BucketStorageBuilder::default()
+            .drives(Arc::clone(&paths))
+            .elem(2, std::mem::size_of::<u64>() as u64)
+            .capacity_pow2(3)
+            .config(BucketStorageConfig {
+                allocated_flag_location: IsAllocatedFlagLocation::Bitmap,
+                ..BucketStorageConfig::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(bitmap.cell_size, src.cell_size);
+        assert!(matches!(
+            bitmap.merge_from(&src),
+            Err(BucketStorageError::LayoutMismatch)
+        ));
+        assert!(matches!(
+            dst.merge_from(&bitmap),
+            Err(BucketStorageError::LayoutMismatch)
+        ));
+        assert!(bitmap.is_empty());
+        assert_eq!(dst.len(), 5);
+    }
+
+    #[test]
+    fn test_allocate_or_hint() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            3,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        // 6 of 8 cells is exactly the default threshold of 0.75, which does not cross it
+        for ix in 0..6 {
+            assert_eq!(storage.allocate_or_hint(ix).unwrap(), ResizeHint::None);
+        }
+        assert_eq!(
+            storage.allocate_or_hint(6).unwrap(),
+            ResizeHint::NeedsResize
+        );
+        assert!(matches!(
+            storage.allocate_or_hint(6),
+            Err(BucketStorageError::AlreadyAllocated)
+        ));
+        assert_eq!(storage.len(), 7);
+
+        storage.config.resize_threshold = 0.9;
+        assert_eq!(
+            storage.allocate_or_hint(7).unwrap(),
+            ResizeHint::NeedsResize
+        );
+        storage.free(7);
+        storage.free(6);
+        assert_eq!(storage.allocate_or_hint(6).unwrap(), ResizeHint::None);
+    }
+
+    #[test]
+    fn test_raw_header() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::default(), Arc::default());
+        assert_eq!(storage.raw_header(0), UID_UNLOCKED);
+        storage.allocate(0, false).unwrap();
+        assert_eq!(storage.raw_header(0), UID_LOCKED);
+
+        unsafe { storage.set_raw_header(1, 0xdead_beef) };
+        assert_eq!(storage.raw_header(1), 0xdead_beef);
+        assert!(!storage.is_free(1));
+        // count does not know about the cell allocated behind its back
+        assert_eq!(storage.len(), 1);
+        storage.reconcile_count();
+        assert_eq!(storage.len(), 2);
+
+        unsafe { storage.set_raw_header(1, UID_UNLOCKED) };
+        assert!(storage.is_free(1));
+    }
+
+    #[test]
+    fn test_capacity_bytes() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            2,
+            std::mem::size_of::<u64>() as u64,
+            4,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(
+            storage.capacity_bytes(),
+            storage.capacity() * storage.cell_size
+        );
+        assert_eq!(
+            storage.cell_size,
+            storage.cell_payload_size() + storage.header_size() as u64
+        );
+        assert_eq!(
+            storage.total_bytes_including_header(),
+            storage.capacity_bytes()
+        );
+    }
+
+    #[test]
+    fn test_sharded_bucket_storage_routing() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let sharded =
+            ShardedBucketStorage::new_with_capacity(Arc::new(paths), 4, 1, 8, 2, 1, Arc::default());
+        assert_eq!(sharded.num_shards(), 4);
+        assert_eq!(sharded.locate(0), (0, 0));
+        assert_eq!(sharded.locate(3), (0, 3));
+        assert_eq!(sharded.locate(1 << 62), (1, 0));
+        assert_eq!(sharded.locate((2 << 62) | 1), (2, 1));
+        assert_eq!(sharded.locate(u64::MAX), (3, u64::MAX >> 2));
+        for (shard, ix) in [(0, 0), (1, 3), (2, 1), (3, 2)] {
+            let key = sharded.key_of(shard, ix);
+            assert_eq!(sharded.shard_of(key), shard);
+            assert_eq!(sharded.locate(key), (shard, ix));
+        }
+
+        // a single shard holds every key
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let single =
+            ShardedBucketStorage::new_with_capacity(Arc::new(paths), 1, 1, 8, 2, 1, Arc::default());
+        assert_eq!(single.locate(u64::MAX), (0, u64::MAX));
+        assert_eq!(single.key_of(0, 3), 3);
+    }
+
+    #[test]
+    fn test_sharded_bucket_storage_shards_are_independent() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut sharded =
+            ShardedBucketStorage::new_with_capacity(Arc::new(paths), 2, 1, 8, 2, 1, Arc::default());
+        let key0 = sharded.key_of(0, 1);
+        let key1 = sharded.key_of(1, 1);
+        sharded.allocate(key0).unwrap();
+        *sharded.get_mut::<u64>(key0) = 10;
+        // the same cell index in another shard is a different cell
+        assert!(sharded.is_free(key1));
+        sharded.allocate(key1).unwrap();
+        *sharded.get_mut::<u64>(key1) = 11;
+        assert_eq!(*sharded.get::<u64>(key0), 10);
+        assert_eq!(*sharded.get::<u64>(key1), 11);
+        assert!(matches!(
+            sharded.allocate(key0),
+            Err(BucketStorageError::AlreadyAllocated)
+        ));
+        assert_eq!(sharded.shard(0).len(), 1);
+        assert_eq!(sharded.shard(1).len(), 1);
+        assert_eq!(sharded.len(), 2);
+
+        sharded.free(key0);
+        assert!(sharded.is_free(key0));
+        assert!(!sharded.is_free(key1));
+        assert_eq!(sharded.shard(0).len(), 0);
+
+        // growing one shard leaves the other alone, and keys keep their cells
+        let key = sharded.key_of(1, 6);
+        assert!(matches!(
+            sharded.allocate(key),
+            Err(BucketStorageError::IndexOutOfBounds { ix: 6, capacity: 4 })
+        ));
+        sharded.grow_shard(1, 3).unwrap();
+        assert_eq!(sharded.shard(0).capacity(), 4);
+        assert_eq!(sharded.shard(1).capacity(), 8);
+        sharded.allocate(key).unwrap();
+        assert_eq!(*sharded.get::<u64>(key1), 11);
+        assert_eq!(sharded.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_file_now() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let stats = Arc::<BucketStats>::default();
+        let mut storage =
+            BucketStorage::new(Arc::new(paths), 1, 8, 1, Arc::clone(&stats), Arc::default());
+        storage.allocate(0, false).unwrap();
+        *storage.get_mut::<u64>(0) = 42;
+        let path = storage.file_path().unwrap().to_path_buf();
+        assert!(path.exists());
+
+        storage.remove_file_now().unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            stats.bytes_freed.load(Ordering::Relaxed),
+            storage.capacity_bytes()
+        );
+        assert!(storage.try_get::<u64>(0).is_none());
+        assert_eq!(*storage.get::<u64>(0), 0);
+        assert!(storage.is_free(0));
+        assert_eq!(storage.len(), 0);
+        assert!(matches!(
+            storage.allocate(1, false),
+            Err(BucketStorageError::FileRemoved)
+        ));
+        storage.remove_file_now().unwrap();
+
+        // dropping does not try to remove the file again, or count its bytes twice
+        std::fs::write(&path, b"not ours").unwrap();
+        drop(storage);
+        assert!(path.exists());
+        assert_eq!(
+            stats.bytes_freed.load(Ordering::Relaxed),
+            stats.bytes_allocated.load(Ordering::Relaxed)
+        );
+    }
+
+    #[test]
+    fn test_try_new_map_seeded_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let tmpdirs = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        let drives = tmpdirs
+            .iter()
+            .map(|tmpdir| tmpdir.path().to_path_buf())
+            .collect::<Vec<_>>();
+        let stats = BucketStats::default();
+        let config = BucketStorageConfig::default();
+        let new_map = |seed| {
+            BucketStorage::try_new_map(
+                &drives,
+                8,
+                1,
+                &stats,
+                &config,
+                &mut StdRng::seed_from_u64(seed),
+            )
+            .unwrap()
+            .1
+        };
+        for seed in 0..8 {
+            // the drive is the first of the drives shuffled by the same rng
+            let mut expected = (0..drives.len()).collect::<Vec<_>>();
+            expected.shuffle(&mut StdRng::seed_from_u64(seed));
+            let path = new_map(seed);
+            assert_eq!(path.parent().unwrap(), drives[expected[0]]);
+            std::fs::remove_file(&path).unwrap();
+            // the same seed picks the same drive and file name again
+            assert_eq!(new_map(seed), path);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_capacity_pow2() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            3,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(storage.capacity_pow2(), 3);
+        assert_eq!(
+            storage.capacity_pow2() as u32,
+            storage.capacity().trailing_zeros()
+        );
+        storage.grow_in_place(5).unwrap();
+        assert_eq!(storage.capacity_pow2(), 5);
+        assert_eq!(
+            storage.capacity_pow2() as u32,
+            storage.capacity().trailing_zeros()
+        );
+    }
+
+    #[test]
+    fn test_bucket_storage_builder() {
+        let tmpdir = tempdir().unwrap();
+        let drives = Arc::new(vec![tmpdir.path().to_path_buf()]);
+        let stats = Arc::<BucketStats>::default();
+        let count = Arc::<AtomicU64>::default();
+        let storage = BucketStorageBuilder::default()
+            .drives(Arc::clone(&drives))
+            .elem(2, std::mem::size_of::<u64>() as u64)
+            .capacity_pow2(3)
+            .max_search(4)
+            .stats(Arc::clone(&stats))
+            .count(Arc::clone(&count))
+            .build()
+            .unwrap();
+        assert!(Arc::ptr_eq(&storage.stats, &stats));
+        assert!(Arc::ptr_eq(&storage.count, &count));
+        assert_eq!(storage.drives(), &drives);
+        assert_eq!(storage.capacity_pow2(), 3);
+        assert_eq!(storage.max_search(), 4);
+        assert_eq!(
+            storage.cell_size,
+            BucketStorage::cell_size_for(std::mem::size_of::<u64>() as u64, 2)
+        );
+
+        // without `stats`, the storage gets stats of its own
+        let unshared = BucketStorageBuilder::default()
+            .drives(drives)
+            .elem(1, 8)
+            .build()
+            .unwrap();
+        assert!(!Arc::ptr_eq(&unshared.stats, &stats));
+        assert_eq!(unshared.capacity_pow2(), DEFAULT_CAPACITY_POW2);
+    }
+
+    #[test]
+    fn test_open_existing_and_repair() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        for ix in 0..3 {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix + 10;
+        }
+        storage.commit(0).unwrap();
+        storage.allocate_with_uid(5, 42).unwrap();
+        storage.commit(5).unwrap();
+        assert!(storage.is_committed(0));
+        assert!(!storage.is_committed(1));
+        assert_eq!(storage.uid(5), Some(42));
+        // committed cells are freed as usual, which clears the mark
+        storage.commit(2).unwrap();
+        storage.free(2);
+        assert!(!storage.is_committed(2));
+        storage.allocate(2, false).unwrap();
+        // cells 1 and 2 are allocated but not committed, as after a crash before their writes
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2();
+        let path = storage.into_persisted();
+
+        let open = || {
+            BucketStorage::open_existing_and_repair(
+                path.clone(),
+                cell_size,
+                capacity_pow2,
+                1,
+                Arc::default(),
+                Arc::default(),
+                BucketStorageConfig::default(),
+            )
+            .unwrap()
+        };
+        let (storage, repaired) = open();
+        assert_eq!(repaired, 2);
+        assert_eq!(storage.allocated_indices().collect::<Vec<_>>(), vec![0, 5]);
+        assert_eq!(storage.len(), 2);
+        assert_eq!(*storage.get::<u64>(0), 10);
+        assert_eq!(*storage.get::<u64>(1), 0);
+        assert_eq!(storage.uid(5), Some(42));
+        drop(storage);
+
+        // nothing is left to repair
+        let (mut storage, repaired) = open();
+        assert_eq!(repaired, 0);
+        storage.free_with_uid(5, 42).unwrap();
+        assert!(matches!(
+            storage.allocate_with_uid(5, COMMITTED | 42),
+            Err(BucketStorageError::ReservedUid)
+        ));
+        drop(storage);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_open_existing_and_repair_after_crash() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        storage.allocate(0, false).unwrap();
+        *storage.get_mut::<u64>(0) = 10;
+        storage.commit(0).unwrap();
+        let cell_size = storage.cell_size;
+        let capacity_pow2 = storage.capacity_pow2();
+        let path = storage.into_persisted();
+        let open = |repair: bool| {
+            if repair {
+                BucketStorage::open_existing_and_repair(
+                    path.clone(),
+                    cell_size,
+                    capacity_pow2,
+                    1,
+                    Arc::default(),
+                    Arc::default(),
+                    BucketStorageConfig::default(),
+                )
+                .map(|(storage, repaired)| (storage, Some(repaired)))
+            } else {
+                BucketStorage::open_existing(
+                    path.clone(),
+                    cell_size,
+                    capacity_pow2,
+                    1,
+                    Arc::default(),
+                    Arc::default(),
+                    BucketStorageConfig::default(),
+                )
+                .map(|storage| (storage, None))
+            }
+        };
+
+        // the process dies after allocating a cell it never commits
+        let (mut storage, _) = open(false).unwrap();
+        storage.allocate(1, false).unwrap();
+        *storage.get_mut::<u64>(1) = 11;
+        std::mem::forget(storage);
+        let (storage, repaired) = open(true).unwrap();
+        assert_eq!(repaired, Some(1));
+        assert_eq!(storage.allocated_indices().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(*storage.get::<u64>(0), 10);
+        drop(storage);
+
+        // a recorded checksum that no longer matches, as left by a writer that did not clear it
+        let mut contents = std::fs::read(&path).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, contents).unwrap();
+        assert!(matches!(
+            open(false),
+            Err(BucketStorageError::ChecksumMismatch)
+        ));
+        let (storage, repaired) = open(true).unwrap();
+        assert_eq!(repaired, Some(0));
+        assert_eq!(*storage.get::<u64>(0), 10);
+        drop(storage);
+        // repairing cleared the stale checksum, and a clean close recorded a matching one
+        let (storage, _) = open(false).unwrap();
+        drop(storage);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_iter_cells_mut() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            4,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        for ix in [1, 4, 9, 15] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix * 100;
+        }
+        for (ix, cell) in storage.iter_cells_mut::<u64>() {
+            assert_eq!(*cell, ix * 100);
+            *cell *= 2;
+        }
+        assert_eq!(
+            storage.export_allocated::<u64>(),
+            vec![(1, 200), (4, 800), (9, 1800), (15, 3000)]
+        );
+        // free cells are not visited
+        assert_eq!(*storage.get::<u64>(0), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_try_resized_falls_back_on_enospc() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            3,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        for ix in [0, 3, 7] {
+            storage.allocate(ix, false).unwrap();
+            *storage.get_mut::<u64>(ix) = ix + 1;
+        }
+
+        // the drive only has space for 2^4 cells
+        let mut attempts = vec![];
+        let resized = storage
+            .try_resized_with(6, |capacity_pow2| {
+                attempts.push(capacity_pow2);
+                if capacity_pow2 > 4 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOSPC).into());
+                }
+                BucketStorageBuilder::default()
+                    .drives(Arc::clone(&storage.drives))
+                    .elem(1, storage.cell_payload_size())
+                    .capacity_pow2(capacity_pow2)
+                    .stats(Arc::clone(&storage.stats))
+                    .count(Arc::clone(&storage.count))
+                    .config(storage.config.clone())
+                    .build()
+            })
+            .unwrap();
+        assert_eq!(attempts, vec![6, 5, 4]);
+        assert_eq!(resized.capacity_pow2(), 4);
+        assert_eq!(resized.len(), 3);
+        assert_eq!(
+            resized.export_allocated::<u64>(),
+            vec![(0, 1), (6, 4), (14, 8)]
+        );
+
+        // no space at any capacity down to the current one
+        let mut attempts = 0;
+        let result = storage.try_resized_with(5, |_| {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(libc::ENOSPC).into())
+        });
+        assert!(matches!(result, Err(BucketStorageError::Io(_))));
+        assert_eq!(attempts, 3);
+
+        // other errors are not retried at a smaller capacity
+        let mut attempts = 0;
+        let result = storage.try_resized_with(5, |_| {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(libc::EINVAL).into())
+        });
+        assert!(matches!(result, Err(BucketStorageError::Io(_))));
+        assert_eq!(attempts, 1);
+
+        let resized = storage.try_resized(5).unwrap();
+        assert_eq!(resized.capacity_pow2(), 5);
+        assert_eq!(*resized.get::<u64>(7 << 2), 8);
+        // the new file has all of its blocks, so a full drive would have failed the resize
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let resized = storage.try_resized(12).unwrap();
+            let metadata = std::fs::metadata(resized.file_path().unwrap()).unwrap();
+            assert!(metadata.blocks() * 512 >= metadata.len());
+        }
+    }
+
+    #[test]
+    fn test_any_allocated() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            4,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(!storage.any_allocated(0, storage.capacity()));
+        storage.allocate(9, false).unwrap();
+        assert!(!storage.any_allocated(0, 9));
+        assert!(!storage.any_allocated(10, 6));
+        assert!(storage.any_allocated(9, 1));
+        assert!(storage.any_allocated(5, 5));
+        assert!(!storage.any_allocated(9, 0));
+
+        // ranges past the end are clamped
+        assert!(storage.any_allocated(8, u64::MAX));
+        assert!(!storage.any_allocated(10, u64::MAX));
+        assert!(!storage.any_allocated(storage.capacity(), 100));
+        assert!(!storage.any_allocated(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn test_mlock_on_open() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let stats = Arc::<BucketStats>::default();
+        let config = BucketStorageConfig {
+            mlock_on_open: true,
+            ..BucketStorageConfig::default()
+        };
+        let mut storage = BucketStorageBuilder::default()
+            .drives(Arc::new(paths))
+            .elem(1, std::mem::size_of::<u64>() as u64)
+            .capacity_pow2(2)
+            .stats(Arc::clone(&stats))
+            .config(config.clone())
+            .build()
+            .unwrap();
+        // mlock may be refused by RLIMIT_MEMLOCK, which only counts a failure
+        assert!(stats.mlock_failures.load(Ordering::Relaxed) <= 1);
+        storage.allocate(1, false).unwrap();
+        *storage.get_mut::<u64>(1) = 5;
+        storage.grow_in_place(3).unwrap();
+        assert!(stats.mlock_failures.load(Ordering::Relaxed) <= 2);
+        assert_eq!(*storage.get::<u64>(1), 5);
+
+        // the old mapping is unlocked when it is replaced or handed off
+        let other = tempdir().unwrap();
+        storage
+            .relocate_to(&Arc::new(vec![other.path().to_path_buf()]))
+            .unwrap();
+        assert!(stats.mlock_failures.load(Ordering::Relaxed) <= 3);
+        let count = Arc::clone(&storage.count);
+        let parts = storage.into_raw_parts().ok().unwrap();
+        let storage =
+            BucketStorage::from_raw_parts(parts, 1, Arc::clone(&stats), count, config.clone())
+                .unwrap();
+        assert!(stats.mlock_failures.load(Ordering::Relaxed) <= 4);
+        assert_eq!(*storage.get::<u64>(1), 5);
+
+        let anonymous = BucketStorageBuilder::default()
+            .elem(1, 8)
+            .capacity_pow2(2)
+            .stats(Arc::clone(&stats))
+            .config(config)
+            .build_anonymous()
+            .unwrap();
+        assert!(stats.mlock_failures.load(Ordering::Relaxed) <= 5);
+        drop(anonymous);
+        drop(storage);
+    }
+
+    #[test]
+    fn test_payload_ptr() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let mut storage = BucketStorage::new(
+            Arc::new(paths),
+            2,
+            std::mem::size_of::<u64>() as u64,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert_eq!(storage.payload_len(), 2 * std::mem::size_of::<u64>());
+        let ix = 3;
+        storage.allocate(ix, false).unwrap();
+        *storage.get_mut::<u64>(ix) = 0x0123_4567_89ab_cdef;
+        let ptr = storage.payload_ptr(ix);
+        assert_eq!(ptr, storage.get_bytes(ix).as_ptr());
+        assert_eq!(
+            unsafe { std::ptr::read(ptr as *const u64) },
+            0x0123_4567_89ab_cdef
+        );
+
+        let ptr = storage.payload_ptr_mut(ix);
+        unsafe { std::ptr::write((ptr as *mut u64).add(1), 7) };
+        assert_eq!(
+            storage.get_cell_slice::<u64>(ix, 2),
+            &[0x0123_4567_89ab_cdef, 7]
+        );
+    }
+
+    #[test]
+    fn test_resize_in_bulk() {
+        let tmpdir = tempdir().unwrap();
+        let paths = Arc::new(vec![tmpdir.path().to_path_buf()]);
+        let mut shards = (0..4u64)
+            .map(|shard| {
+                let mut storage = BucketStorage::new_with_capacity(
+                    Arc::clone(&paths),
+                    1,
+                    std::mem::size_of::<u64>() as u64,
+                    2,
+                    1,
+                    Arc::default(),
+                    Arc::default(),
+                );
+                for ix in 0..=shard {
+                    storage.allocate(ix, false).unwrap();
+                    *storage.get_mut::<u64>(ix) = shard * 10 + ix;
+                }
+                storage
+            })
+            .collect::<Vec<_>>();
+        let counts = shards
+            .iter()
+            .map(|shard| Arc::clone(&shard.count))
+            .collect::<Vec<_>>();
+
+        BucketStorage::resize_in_bulk(&mut shards, 4).unwrap();
+        for (shard, (storage, count)) in shards.iter().zip(&counts).enumerate() {
+            let shard = shard as u64;
+            assert_eq!(storage.capacity_pow2(), 4);
+            assert!(Arc::ptr_eq(&storage.count, count));
+            assert_eq!(storage.len(), shard + 1);
+            assert_eq!(
+                storage.export_allocated::<u64>(),
+                (0..=shard)
+                    .map(|ix| (ix << 2, shard * 10 + ix))
+                    .collect::<Vec<_>>()
+            );
+        }
+        // the files of the old storages were removed
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_validate_index() {
+        let tmpdir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+        let storage = BucketStorage::new_with_capacity(
+            Arc::new(paths),
+            1,
+            std::mem::size_of::<u64>() as u64,
+            3,
+            1,
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(storage.validate_index(0).is_ok());
+        assert!(storage.validate_index(7).is_ok());
+        for ix in [8, 9, u64::MAX] {
+            assert!(matches!(
+                storage.validate_index(ix),
+                Err(BucketStorageError::IndexOutOfBounds { ix: bad, capacity: 8 }) if bad == ix
+            ));
+        }
+    }
+
+    #[test]
+    fn test_per_drive_stats() {
+        let tmpdirs = (0..2).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        let drives = Arc::new(
+            tmpdirs
+                .iter()
+                .map(|tmpdir| tmpdir.path().to_path_buf())
+                .collect::<Vec<_>>(),
+        );
+        let stats = Arc::new(BucketStats::with_drives(drives.len()));
+        let config = BucketStorageConfig {
+            drive_selection: DriveSelection::RoundRobin,
+            sync_on_create: true,
+            ..BucketStorageConfig::default()
+        };
+        let new_storage = || {
+            BucketStorageBuilder::default()
+                .drives(Arc::clone(&drives))
+                .elem(1, std::mem::size_of::<u64>() as u64)
+                .capacity_pow2(8)
+                .stats(Arc::clone(&stats))
+                .config(config.clone())
+                .build()
+                .unwrap()
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        // round robin puts the first file on the first drive only
+        let first = new_storage();
+        assert_eq!(first.drive(), Some(drives[0].as_path()));
+        let per_drive = stats.per_drive().unwrap();
+        assert_eq!(load(&per_drive[0].files_created), 1);
+        assert_eq!(load(&per_drive[1].files_created), 0);
+        assert_eq!(load(&per_drive[1].new_file_us), 0);
+        assert_eq!(load(&per_drive[1].flush_file_us), 0);
+        assert_eq!(load(&per_drive[1].mmap_us), 0);
+
+        let storages = (0..9).map(|_| new_storage()).collect::<Vec<_>>();
+        assert_eq!(storages[0].drive(), Some(drives[1].as_path()));
+        assert_eq!(load(&per_drive[0].files_created), 5);
+        assert_eq!(load(&per_drive[1].files_created), 5);
+        // every file's times went to exactly one drive
+        let sum = |counter: fn(&DriveStats) -> &AtomicU64| {
+            per_drive
+                .iter()
+                .map(|drive| load(counter(drive)))
+                .sum::<u64>()
+        };
+        assert_eq!(sum(|drive| &drive.new_file_us), load(&stats.new_file_us));
+        assert_eq!(
+            sum(|drive| &drive.flush_file_us),
+            load(&stats.flush_file_us)
+        );
+        assert_eq!(sum(|drive| &drive.mmap_us), load(&stats.mmap_us));
+        assert!(per_drive
+            .iter()
+            .all(|drive| load(&drive.new_file_us) + load(&drive.flush_file_us) > 0));
     }
 }
@@ -1,11 +1,20 @@
 #![allow(clippy::integer_arithmetic)]
 mod bucket;
 pub mod bucket_api;
+mod bucket_arena;
 mod bucket_item;
 pub mod bucket_map;
 mod bucket_stats;
 mod bucket_storage;
 mod index_entry;
 
+pub use {
+    bucket_arena::BucketArena,
+    bucket_storage::{
+        BucketStorage, BucketStorageBuilder, BucketStorageConfig, BucketStorageError,
+        ShardedBucketStorage, SyncBucketStorage, TypedBucketStorage,
+    },
+};
+
 pub type MaxSearch = u8;
 pub type RefCount = u64;
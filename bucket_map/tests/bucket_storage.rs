@@ -0,0 +1,26 @@
+use {
+    solana_bucket_map::BucketStorage,
+    std::{path::PathBuf, sync::Arc},
+    tempfile::tempdir,
+};
+
+#[test]
+fn bucket_storage_capacity_bytes() {
+    let tmpdir = tempdir().unwrap();
+    let paths: Vec<PathBuf> = vec![tmpdir.path().to_path_buf()];
+    let elem_size = std::mem::size_of::<u64>() as u64;
+    let storage = BucketStorage::new(
+        Arc::new(paths),
+        1,
+        elem_size,
+        1,
+        Arc::default(),
+        Arc::default(),
+    );
+    let cell_size = BucketStorage::cell_size_for(elem_size, 1);
+    assert_eq!(storage.capacity_bytes(), storage.capacity() * cell_size);
+    assert_eq!(
+        storage.total_bytes_including_header(),
+        storage.capacity_bytes()
+    );
+}